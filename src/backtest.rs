@@ -0,0 +1,542 @@
+//! Backtesting over recorded market data.
+//!
+//! `Backtest` drives a `Strategy` with `Level2` updates, typically
+//! from a `replay::Replay`, and routes the strategy's orders through a
+//! simulated matching engine. Orders, fees and order responses are the
+//! same types the live client uses, so strategy code can be shared.
+//!
+//! Matching follows the rules of the `matching` module shared with
+//! paper trading, with queue-position modeling: an order joins the
+//! back of its price level, and trades at that price consume the queue
+//! ahead of it before filling it. Orders take effect immediately; no
+//! latency is modeled.
+use crate::book::OrderBook;
+use crate::matching::{check_options, reject, MatchEvent, Matcher, SimOrder};
+use crate::paper::PaperFees;
+use crate::pnl::{CostBasis, PnlTracker};
+use crate::structs::order::{Order, OrderId, OrderResponse, OrderSide, RejectReason};
+use crate::structs::wsfeed::{FillLiquidity, Level2, MarketDataMessage};
+use crate::types::Result;
+use crate::util::parse_f64;
+use crate::wsfeed::GeminiStream;
+use futures::StreamExt;
+use std::collections::VecDeque;
+
+/// Fill of a simulated order.
+#[derive(Debug, Clone)]
+pub struct BacktestFill {
+    pub order_id: OrderId,
+    pub client_order_id: Option<String>,
+    pub side: OrderSide,
+    pub price: f64,
+    pub amount: f64,
+    pub fee: f64,
+    pub liquidity: FillLiquidity,
+
+    /// Index of the market data update during which the fill occurred.
+    pub update: usize,
+}
+
+/// Summary of a backtest.
+#[derive(Debug, Clone, Default)]
+pub struct BacktestReport {
+    pub fills: Vec<BacktestFill>,
+
+    /// Number of orders sent by the strategy, including rejected ones.
+    pub orders: usize,
+
+    /// Realized PnL before fees, in the quote currency.
+    pub realized_pnl: f64,
+
+    /// Unrealized PnL of the final inventory, marked at the final mid
+    /// price.
+    pub unrealized_pnl: f64,
+
+    pub fees: f64,
+
+    /// Realized plus unrealized PnL, after fees.
+    pub net_pnl: f64,
+
+    /// Final position in the base currency; negative when short.
+    pub inventory: f64,
+
+    /// Traded quantity in the base currency.
+    pub volume: f64,
+
+    /// Largest drop of `net_pnl` from a previous peak.
+    pub max_drawdown: f64,
+
+    /// `net_pnl` after every update.
+    pub equity: Vec<f64>,
+}
+
+/// Matching engine for a single symbol.
+struct Engine {
+    symbol: String,
+    quote: String,
+    fees: PaperFees,
+    matcher: Matcher,
+    next_order_id: u64,
+    next_trade_id: u64,
+    sent: usize,
+    update: usize,
+    pnl: PnlTracker,
+    fills: Vec<BacktestFill>,
+    /// Fills not yet delivered to the strategy.
+    pending: VecDeque<BacktestFill>,
+}
+
+impl Engine {
+    fn fill(&mut self, order: &SimOrder, price: f64, amount: f64, liquidity: FillLiquidity) {
+        let bps = match liquidity {
+            FillLiquidity::Maker => self.fees.maker_fee_bps,
+            _ => self.fees.taker_fee_bps,
+        };
+        let fee = amount * price * bps / 10_000.0;
+
+        self.next_trade_id += 1;
+        self.pnl.apply(
+            &self.symbol,
+            &self.next_trade_id.to_string(),
+            order.side,
+            amount,
+            price,
+            fee,
+            &self.quote,
+        );
+        let fill = BacktestFill {
+            order_id: order.order_id,
+            client_order_id: order.client_order_id.clone(),
+            side: order.side,
+            price,
+            amount,
+            fee,
+            liquidity,
+            update: self.update,
+        };
+        self.fills.push(fill.clone());
+        self.pending.push_back(fill);
+    }
+
+    /// Record the fills among matcher events.
+    fn record(&mut self, matches: Vec<MatchEvent>) {
+        for event in matches {
+            if let MatchEvent::Fill {
+                order,
+                price,
+                amount,
+                liquidity,
+            } = event
+            {
+                self.fill(&order, parse_f64(&price), amount, liquidity);
+            }
+        }
+    }
+
+    fn new_order(&mut self, order: &Order) -> Result<OrderResponse> {
+        self.sent += 1;
+        if order.symbol().to_lowercase() != self.symbol {
+            return Err(reject(order, RejectReason::InvalidSymbol));
+        }
+        check_options(order)?;
+
+        self.next_order_id += 1;
+        let sim = SimOrder::new(OrderId(self.next_order_id), order);
+        let mut matches = Vec::new();
+        let sim = self.matcher.submit(sim, &mut matches);
+        self.record(matches);
+        Ok(sim.response())
+    }
+
+    fn apply(&mut self, l2: &Level2) {
+        self.update += 1;
+        let mut matches = Vec::new();
+        self.matcher.apply(l2, &mut matches);
+        self.record(matches);
+    }
+
+    fn net_pnl(&self) -> f64 {
+        let pnl = match self.pnl.symbol(&self.symbol) {
+            Some(pnl) => pnl,
+            None => return 0.0,
+        };
+        let unrealized = self
+            .matcher
+            .book()
+            .mid()
+            .map_or(0.0, |mid| pnl.unrealized(mid));
+        pnl.net_realized() + unrealized
+    }
+}
+
+/// Access to the simulated exchange from a strategy callback.
+pub struct BacktestContext<'a> {
+    engine: &'a mut Engine,
+}
+
+impl<'a> BacktestContext<'a> {
+    /// Current book, including liquidity taken by simulated orders.
+    pub fn book(&self) -> &OrderBook {
+        self.engine.matcher.book()
+    }
+
+    /// Send an order. It is matched immediately against the book.
+    ///
+    /// Orders Gemini would refuse fail with the same `GError::Gemini`
+    /// error, and are not assigned an order id.
+    pub fn new_order(&mut self, order: &Order) -> Result<OrderResponse> {
+        self.engine.new_order(order)
+    }
+
+    /// Cancel an open order. Returns `None` if the order is not open.
+    pub fn cancel_order(&mut self, order_id: OrderId) -> Option<OrderResponse> {
+        let order = self
+            .engine
+            .matcher
+            .cancel(order_id, RejectReason::Requested)?;
+        Some(order.response())
+    }
+
+    /// Cancel every open order.
+    pub fn cancel_all_orders(&mut self) {
+        let ids: Vec<OrderId> = self.engine.matcher.orders().map(|o| o.order_id).collect();
+        for id in ids {
+            self.engine.matcher.cancel(id, RejectReason::Requested);
+        }
+    }
+
+    pub fn open_orders(&self) -> Vec<OrderResponse> {
+        self.engine.matcher.orders().map(|o| o.response()).collect()
+    }
+
+    /// Quantity ahead of an open order in its price level's queue.
+    /// Stop-limit orders that have not triggered have no queue
+    /// position.
+    pub fn queue_ahead(&self, order_id: OrderId) -> Option<f64> {
+        self.engine.matcher.queue_ahead(order_id)
+    }
+
+    /// Position in the base currency; negative when short.
+    pub fn position(&self) -> f64 {
+        self.engine
+            .pnl
+            .symbol(&self.engine.symbol)
+            .map_or(0.0, |p| p.position())
+    }
+
+    pub fn pnl(&self) -> &PnlTracker {
+        &self.engine.pnl
+    }
+
+    /// Index of the current market data update.
+    pub fn update(&self) -> usize {
+        self.engine.update
+    }
+}
+
+/// Strategy driven by a `Backtest`.
+pub trait Strategy {
+    /// Called after every `Level2` update has been applied.
+    fn on_book(&mut self, ctx: &mut BacktestContext<'_>);
+
+    /// Called for every fill of the strategy's orders.
+    fn on_fill(&mut self, _ctx: &mut BacktestContext<'_>, _fill: &BacktestFill) {}
+}
+
+pub struct Backtest<S> {
+    engine: Engine,
+    strategy: S,
+    equity: Vec<f64>,
+}
+
+impl<S: Strategy> Backtest<S> {
+    /// Backtest `strategy` on `symbol`. PnL is computed with the
+    /// average cost basis.
+    pub fn new(symbol: &str, fees: PaperFees, strategy: S) -> Self {
+        let symbol = symbol.to_lowercase();
+        let quote = Order::currencies(&symbol)
+            .map(|(_, quote)| quote)
+            .unwrap_or_default();
+        Backtest {
+            engine: Engine {
+                matcher: Matcher::new(&symbol, true),
+                symbol,
+                quote,
+                fees,
+                next_order_id: 0,
+                next_trade_id: 0,
+                sent: 0,
+                update: 0,
+                pnl: PnlTracker::new(CostBasis::AverageCost),
+                fills: Vec::new(),
+                pending: VecDeque::new(),
+            },
+            strategy,
+            equity: Vec::new(),
+        }
+    }
+
+    pub fn strategy(&self) -> &S {
+        &self.strategy
+    }
+
+    pub fn into_strategy(self) -> S {
+        self.strategy
+    }
+
+    fn deliver_fills(&mut self) {
+        while let Some(fill) = self.engine.pending.pop_front() {
+            let mut ctx = BacktestContext {
+                engine: &mut self.engine,
+            };
+            self.strategy.on_fill(&mut ctx, &fill);
+        }
+    }
+
+    /// Apply a single update and run the strategy on it. Updates for
+    /// other symbols are ignored.
+    pub fn apply(&mut self, l2: &Level2) {
+        if l2.symbol.to_lowercase() != self.engine.symbol {
+            return;
+        }
+        self.engine.apply(l2);
+        self.deliver_fills();
+
+        let mut ctx = BacktestContext {
+            engine: &mut self.engine,
+        };
+        self.strategy.on_book(&mut ctx);
+        self.deliver_fills();
+
+        self.equity.push(self.engine.net_pnl());
+    }
+
+    /// Apply a market data message. Only `Level2` messages drive the
+    /// backtest.
+    pub fn apply_message(&mut self, msg: &MarketDataMessage) {
+        if let MarketDataMessage::Level2(l2) = msg {
+            self.apply(l2);
+        }
+    }
+
+    /// Run the backtest over a whole feed, e.g. a `Replay`.
+    ///
+    /// Stops at the first error, including messages that failed to
+    /// decode.
+    pub async fn run<F: GeminiStream<MarketDataMessage>>(
+        mut self,
+        mut feed: F,
+    ) -> Result<BacktestReport> {
+        while let Some(msg) = feed.next().await {
+            match msg? {
+                MarketDataMessage::InternalError(e) => return Err(e),
+                msg => self.apply_message(&msg),
+            }
+        }
+        Ok(self.report())
+    }
+
+    /// Report of the backtest so far.
+    pub fn report(&self) -> BacktestReport {
+        let engine = &self.engine;
+        let mut report = BacktestReport {
+            fills: engine.fills.clone(),
+            orders: engine.sent,
+            equity: self.equity.clone(),
+            ..BacktestReport::default()
+        };
+
+        if let Some(pnl) = engine.pnl.symbol(&engine.symbol) {
+            report.realized_pnl = pnl.realized();
+            report.fees = pnl.fees();
+            report.unrealized_pnl = engine
+                .matcher
+                .book()
+                .mid()
+                .map_or(0.0, |mid| pnl.unrealized(mid));
+            report.net_pnl = pnl.net_realized() + report.unrealized_pnl;
+            report.inventory = pnl.position();
+            report.volume = pnl.volume();
+        }
+
+        let mut peak = 0.0_f64;
+        for &equity in &self.equity {
+            peak = peak.max(equity);
+            report.max_drawdown = report.max_drawdown.max(peak - equity);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::order::OrderBuilder;
+    use crate::structs::wsfeed::{Level2Change, Trade};
+    use crate::types::GError;
+
+    struct Idle;
+
+    impl Strategy for Idle {
+        fn on_book(&mut self, _ctx: &mut BacktestContext<'_>) {}
+    }
+
+    fn l2(changes: &[(OrderSide, &str, &str)], trades: &[(OrderSide, &str, &str)]) -> Level2 {
+        Level2 {
+            symbol: "BTCUSD".to_string(),
+            changes: Some(
+                changes
+                    .iter()
+                    .map(|(side, price, quantity)| Level2Change {
+                        order: *side,
+                        price: price.to_string(),
+                        quantity: quantity.to_string(),
+                    })
+                    .collect(),
+            ),
+            trades: Some(
+                trades
+                    .iter()
+                    .map(|(side, price, quantity)| Trade {
+                        price: price.to_string(),
+                        quantity: quantity.to_string(),
+                        side: *side,
+                    })
+                    .collect(),
+            ),
+            auction_events: None,
+        }
+    }
+
+    /// Backtest with a book of 1 BTC at 100/101 and 2 BTC at 99/102.
+    fn backtest() -> Backtest<Idle> {
+        let fees = PaperFees {
+            maker_fee_bps: 10.0,
+            taker_fee_bps: 20.0,
+        };
+        let mut bt = Backtest::new("btcusd", fees, Idle);
+        bt.apply(&l2(
+            &[
+                (OrderSide::Buy, "100.00", "1"),
+                (OrderSide::Buy, "99.00", "2"),
+                (OrderSide::Sell, "101.00", "1"),
+                (OrderSide::Sell, "102.00", "2"),
+            ],
+            &[],
+        ));
+        bt
+    }
+
+    fn send(bt: &mut Backtest<Idle>, order: OrderBuilder) -> Result<OrderResponse> {
+        let mut ctx = BacktestContext {
+            engine: &mut bt.engine,
+        };
+        ctx.new_order(&order.build().unwrap())
+    }
+
+    fn limit(side: OrderSide, amount: f64, price: f64) -> OrderBuilder {
+        OrderBuilder::limit("btcusd", side, amount, price)
+    }
+
+    fn queue_ahead(bt: &mut Backtest<Idle>, order_id: OrderId) -> Option<f64> {
+        let ctx = BacktestContext {
+            engine: &mut bt.engine,
+        };
+        ctx.queue_ahead(order_id)
+    }
+
+    #[test]
+    fn trades_at_price_consume_the_queue_first() {
+        let mut bt = backtest();
+        let bid = send(&mut bt, limit(OrderSide::Buy, 1.0, 100.0)).unwrap();
+        assert!(bid.is_live);
+        assert_eq!(queue_ahead(&mut bt, bid.order_id), Some(1.0));
+
+        // Buyers lifting offers do not fill bids.
+        bt.apply(&l2(&[], &[(OrderSide::Buy, "100.00", "5")]));
+        assert_eq!(queue_ahead(&mut bt, bid.order_id), Some(1.0));
+
+        bt.apply(&l2(&[], &[(OrderSide::Sell, "100.00", "0.6")]));
+        assert!(bt.report().fills.is_empty());
+        assert!((queue_ahead(&mut bt, bid.order_id).unwrap() - 0.4).abs() < 1e-9);
+
+        bt.apply(&l2(&[], &[(OrderSide::Sell, "100.00", "0.9")]));
+        let report = bt.report();
+        assert_eq!(report.fills.len(), 1);
+        assert!((report.fills[0].amount - 0.5).abs() < 1e-9);
+        assert_eq!(report.fills[0].price, 100.0);
+        assert_eq!(report.fills[0].liquidity, FillLiquidity::Maker);
+        assert_eq!(report.fills[0].update, 4);
+    }
+
+    #[test]
+    fn cancellations_shrink_the_queue() {
+        let mut bt = backtest();
+        let bid = send(&mut bt, limit(OrderSide::Buy, 1.0, 99.0)).unwrap();
+        assert_eq!(queue_ahead(&mut bt, bid.order_id), Some(2.0));
+
+        bt.apply(&l2(&[(OrderSide::Buy, "99.00", "0.5")], &[]));
+        assert_eq!(queue_ahead(&mut bt, bid.order_id), Some(0.5));
+
+        // Growing the level again adds to the back of the queue.
+        bt.apply(&l2(&[(OrderSide::Buy, "99.00", "3")], &[]));
+        assert_eq!(queue_ahead(&mut bt, bid.order_id), Some(0.5));
+
+        // Trades through the price fill regardless of the queue.
+        bt.apply(&l2(&[], &[(OrderSide::Sell, "98.00", "0.25")]));
+        let report = bt.report();
+        assert_eq!(report.fills.len(), 1);
+        assert_eq!(report.fills[0].price, 99.0);
+    }
+
+    #[test]
+    fn refused_and_fill_or_kill_orders() {
+        let mut bt = backtest();
+        let reason = |result: Result<OrderResponse>| match result {
+            Err(GError::Gemini(e)) => e.reject_reason(),
+            other => panic!("expected a Gemini error, got {:?}", other),
+        };
+
+        let other = OrderBuilder::limit("ethusd", OrderSide::Buy, 1.0, 100.0);
+        assert_eq!(reason(send(&mut bt, other)), RejectReason::InvalidSymbol);
+        let auction = limit(OrderSide::Buy, 1.0, 100.0).auction_only();
+        assert_eq!(
+            reason(send(&mut bt, auction)),
+            RejectReason::InvalidOrderType
+        );
+
+        let fok = send(&mut bt, limit(OrderSide::Buy, 5.0, 102.0).fill_or_kill()).unwrap();
+        assert!(fok.is_cancelled);
+        assert_eq!(fok.reason, Some(RejectReason::FillOrKillWouldNotFill));
+
+        let report = bt.report();
+        assert_eq!(report.orders, 3);
+        assert!(report.fills.is_empty());
+    }
+
+    #[test]
+    fn report_tracks_pnl_and_drawdown() {
+        let mut bt = backtest();
+        let buy = send(&mut bt, limit(OrderSide::Buy, 1.0, 101.0)).unwrap();
+        assert!(!buy.is_live);
+        let sell = send(&mut bt, limit(OrderSide::Sell, 1.0, 103.0)).unwrap();
+        assert!(sell.is_live);
+
+        // The bid drops, marking the long position down to a 100.5 mid.
+        bt.apply(&l2(&[(OrderSide::Buy, "100.00", "0")], &[]));
+        bt.apply(&l2(&[], &[(OrderSide::Buy, "103.00", "1")]));
+
+        let report = bt.report();
+        assert_eq!(report.fills.len(), 2);
+        assert_eq!(report.fills[0].liquidity, FillLiquidity::Taker);
+        assert_eq!(report.fills[1].liquidity, FillLiquidity::Maker);
+        assert!((report.realized_pnl - 2.0).abs() < 1e-9);
+        assert!((report.fees - (0.202 + 0.103)).abs() < 1e-9);
+        assert!((report.net_pnl - 1.695).abs() < 1e-9);
+        assert_eq!(report.inventory, 0.0);
+        assert!((report.volume - 2.0).abs() < 1e-9);
+        assert_eq!(report.equity.len(), 3);
+        assert!((report.equity[1] - (-0.5 - 0.202)).abs() < 1e-9);
+        assert!((report.max_drawdown - 0.702).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,339 @@
+//! Live account balances maintained from order events.
+//!
+//! `BalanceTracker` starts from a `Private::balances` snapshot and
+//! applies the fills of the order events feed, so that balances are
+//! current without re-polling the REST API. Amounts reserved by open
+//! orders are tracked from the `remaining_amount` of each order's
+//! latest event; subscribe to the order events feed before building
+//! the tracker so that the `initial` events for already-open orders
+//! are applied.
+//!
+//! Fills are applied once per trade id, and fills or events with
+//! malformed amounts or unknown symbols are skipped rather than
+//! applied as zero.
+use crate::structs::order::{Order, OrderId, OrderSide};
+use crate::structs::private::AccountBalance;
+use crate::structs::wsfeed::{Fill, OrderEventType, OrderMessage, OrderStatus};
+use crate::util::{parse_f64, try_parse_f64};
+use std::collections::{HashMap, HashSet};
+
+/// Balance of a single currency.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Balance {
+    pub total: f64,
+    pub reserved: f64,
+}
+
+impl Balance {
+    /// Amount not reserved by open orders.
+    pub fn available(&self) -> f64 {
+        self.total - self.reserved
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Reservation {
+    currency: String,
+    amount: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BalanceTracker {
+    totals: HashMap<String, f64>,
+    reservations: HashMap<OrderId, Reservation>,
+
+    /// Trade ids already applied, so that replayed fills are not
+    /// double counted.
+    seen: HashSet<String>,
+}
+
+impl BalanceTracker {
+    pub fn new(balances: &[AccountBalance]) -> BalanceTracker {
+        let mut tracker = BalanceTracker::default();
+        tracker.reset(balances);
+        tracker
+    }
+
+    /// Replace all totals with a fresh `Private::balances` snapshot.
+    /// Reservations of open orders are kept.
+    pub fn reset(&mut self, balances: &[AccountBalance]) {
+        self.totals = balances
+            .iter()
+            .map(|b| (b.currency.to_uppercase(), parse_f64(&b.amount)))
+            .collect();
+    }
+
+    fn add(&mut self, currency: &str, amount: f64) {
+        *self.totals.entry(currency.to_uppercase()).or_insert(0.0) += amount;
+    }
+
+    /// Apply a fill of an order on `symbol`. Returns false, leaving
+    /// balances untouched, if a fill with the same trade id was already
+    /// applied, the symbol is unknown or the fill is malformed.
+    ///
+    /// Fills on perpetual contracts change a position rather than a
+    /// balance, so only their fees are applied.
+    pub fn apply_fill(&mut self, symbol: &str, side: OrderSide, fill: &Fill) -> bool {
+        if self.seen.contains(&fill.trade_id) {
+            return false;
+        }
+        let (base, quote) = match Order::currencies(symbol) {
+            Some(currencies) => currencies,
+            None => return false,
+        };
+        let parsed = (
+            try_parse_f64(&fill.amount),
+            try_parse_f64(&fill.price),
+            try_parse_f64(&fill.fee),
+        );
+        let (amount, price, fee) = match parsed {
+            (Some(amount), Some(price), Some(fee)) => (amount, price, fee),
+            _ => return false,
+        };
+        self.seen.insert(fill.trade_id.clone());
+
+        self.add(&fill.fee_currency, -fee);
+        if Order::is_perpetual(symbol) {
+            return true;
+        }
+        let notional = amount * price;
+        match side {
+            OrderSide::Buy => {
+                self.add(&base, amount);
+                self.add(&quote, -notional);
+            }
+            OrderSide::Sell => {
+                self.add(&base, -amount);
+                self.add(&quote, notional);
+            }
+        }
+        true
+    }
+
+    /// Apply an order event: its fill, if any, and the amount it still
+    /// reserves. A reservation with a malformed remaining amount or
+    /// price is left as it was.
+    pub fn apply_event(&mut self, status: &OrderStatus) {
+        if let Some(fill) = &status.fill {
+            self.apply_fill(&status.symbol, status.side, fill);
+        }
+
+        let closed = matches!(
+            status.event_type,
+            OrderEventType::Rejected | OrderEventType::Cancelled | OrderEventType::Closed
+        );
+        if closed || !status.is_live || Order::is_perpetual(&status.symbol) {
+            self.reservations.remove(&status.order_id);
+            return;
+        }
+
+        let (base, quote) = match Order::currencies(&status.symbol) {
+            Some(currencies) => currencies,
+            None => return,
+        };
+        let remaining = match try_parse_f64(&status.remaining_amount) {
+            Some(remaining) => remaining,
+            None => return,
+        };
+        let reservation = match (status.side, try_parse_f64(&status.price)) {
+            (OrderSide::Buy, Some(price)) => Reservation {
+                currency: quote,
+                amount: remaining * price,
+            },
+            (OrderSide::Buy, None) => return,
+            (OrderSide::Sell, _) => Reservation {
+                currency: base,
+                amount: remaining,
+            },
+        };
+        self.reservations.insert(status.order_id, reservation);
+    }
+
+    /// Apply every order event in a message from the order events feed.
+    pub fn apply_message(&mut self, msg: &OrderMessage) {
+        if let OrderMessage::Orders(orders) = msg {
+            for status in orders {
+                self.apply_event(status);
+            }
+        }
+    }
+
+    /// Balance of a currency such as `USD` or `BTC`.
+    pub fn balance(&self, currency: &str) -> Balance {
+        let currency = currency.to_uppercase();
+        Balance {
+            total: self.totals.get(&currency).copied().unwrap_or(0.0),
+            reserved: self
+                .reservations
+                .values()
+                .filter(|r| r.currency == currency)
+                .map(|r| r.amount)
+                .sum(),
+        }
+    }
+
+    pub fn total(&self, currency: &str) -> f64 {
+        self.balance(currency).total
+    }
+
+    pub fn available(&self, currency: &str) -> f64 {
+        self.balance(currency).available()
+    }
+
+    pub fn reserved(&self, currency: &str) -> f64 {
+        self.balance(currency).reserved
+    }
+
+    /// Balances of every currency seen.
+    pub fn balances(&self) -> HashMap<String, Balance> {
+        self.totals
+            .keys()
+            .chain(self.reservations.values().map(|r| &r.currency))
+            .map(|c| (c.clone(), self.balance(c)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn tracker() -> BalanceTracker {
+        let balance = |currency: &str, amount: &str| AccountBalance {
+            currency: currency.to_string(),
+            amount: amount.to_string(),
+            available: amount.to_string(),
+            available_for_withdrawal: amount.to_string(),
+        };
+        BalanceTracker::new(&[balance("USD", "1000"), balance("btc", "2")])
+    }
+
+    fn fill(trade_id: &str, amount: &str, price: &str, fee: &str, fee_currency: &str) -> Fill {
+        serde_json::from_value(json!({
+            "trade_id": trade_id,
+            "liquidity": "Taker",
+            "price": price,
+            "amount": amount,
+            "fee": fee,
+            "fee_currency": fee_currency,
+        }))
+        .unwrap()
+    }
+
+    fn event(
+        event: &str,
+        side: &str,
+        remaining: &str,
+        live: bool,
+        fill: Option<Fill>,
+    ) -> OrderStatus {
+        let mut status: OrderStatus = serde_json::from_value(json!({
+            "type": event,
+            "order_id": "1",
+            "symbol": "btcusd",
+            "side": side,
+            "order_type": "exchange limit",
+            "timestampms": 0,
+            "is_live": live,
+            "is_cancelled": event == "cancelled",
+            "is_hidden": false,
+            "remaining_amount": remaining,
+            "original_amount": "1",
+            "price": "100.00",
+        }))
+        .unwrap();
+        status.fill = fill;
+        status
+    }
+
+    #[test]
+    fn spot_fills_and_fees() {
+        let mut balances = tracker();
+        assert!(balances.apply_fill(
+            "btcusd",
+            OrderSide::Buy,
+            &fill("1", "1", "100", "0.5", "USD")
+        ));
+        assert!(close(balances.total("BTC"), 3.0));
+        assert!(close(balances.total("usd"), 1000.0 - 100.0 - 0.5));
+
+        // Fees in the base currency come out of the base balance.
+        assert!(balances.apply_fill(
+            "btcusd",
+            OrderSide::Sell,
+            &fill("2", "2", "110", "0.01", "BTC")
+        ));
+        assert!(close(balances.total("BTC"), 3.0 - 2.0 - 0.01));
+        assert!(close(balances.total("USD"), 899.5 + 220.0));
+    }
+
+    #[test]
+    fn perpetual_fills_only_pay_fees() {
+        let mut balances = tracker();
+        let perp = fill("1", "1", "100", "0.05", "GUSD");
+        assert!(balances.apply_fill("btcgusdperp", OrderSide::Buy, &perp));
+        assert!(close(balances.total("GUSD"), -0.05));
+        assert!(close(balances.total("BTC"), 2.0));
+        assert!(close(balances.total("USD"), 1000.0));
+    }
+
+    #[test]
+    fn duplicate_unknown_and_malformed_fills_are_skipped() {
+        let mut balances = tracker();
+        let first = fill("1", "1", "100", "0.5", "USD");
+        assert!(balances.apply_fill("btcusd", OrderSide::Buy, &first));
+        assert!(!balances.apply_fill("btcusd", OrderSide::Buy, &first));
+        assert!(!balances.apply_fill(
+            "nonsense",
+            OrderSide::Buy,
+            &fill("2", "1", "100", "0.5", "USD")
+        ));
+        assert!(!balances.apply_fill(
+            "btcusd",
+            OrderSide::Buy,
+            &fill("3", "1", "abc", "0.5", "USD")
+        ));
+        assert!(!balances.apply_fill("btcusd", OrderSide::Buy, &fill("4", "1", "100", "", "USD")));
+        assert!(close(balances.total("BTC"), 3.0));
+        assert!(close(balances.total("USD"), 899.5));
+
+        // A malformed fill can be applied once corrected.
+        assert!(balances.apply_fill("btcusd", OrderSide::Buy, &fill("3", "1", "100", "0", "USD")));
+        assert!(close(balances.total("BTC"), 4.0));
+    }
+
+    #[test]
+    fn reservations_follow_order_events() {
+        let mut balances = tracker();
+        balances.apply_event(&event("accepted", "buy", "1", true, None));
+        assert!(close(balances.reserved("USD"), 100.0));
+        assert!(close(balances.available("USD"), 900.0));
+
+        let partial = fill("1", "0.25", "100", "0.1", "USD");
+        balances.apply_event(&event("fill", "buy", "0.75", true, Some(partial)));
+        assert!(close(balances.reserved("USD"), 75.0));
+        assert!(close(balances.total("USD"), 1000.0 - 25.0 - 0.1));
+        assert!(close(balances.total("BTC"), 2.25));
+
+        // A malformed remaining amount keeps the last reservation.
+        balances.apply_event(&event("booked", "buy", "?", true, None));
+        assert!(close(balances.reserved("USD"), 75.0));
+
+        balances.apply_event(&event("cancelled", "buy", "0.75", false, None));
+        assert!(close(balances.reserved("USD"), 0.0));
+
+        balances.apply_event(&event("accepted", "sell", "1", true, None));
+        assert!(close(balances.reserved("BTC"), 1.0));
+        let last = fill("2", "1", "100", "0.1", "USD");
+        balances.apply_event(&event("fill", "sell", "0", false, Some(last)));
+        balances.apply_event(&event("closed", "sell", "0", false, None));
+        assert!(close(balances.reserved("BTC"), 0.0));
+        assert!(close(balances.total("BTC"), 1.25));
+        assert!(close(balances.balances()["USD"].available(), 974.9 + 99.9));
+    }
+}
@@ -0,0 +1,465 @@
+//! Local level-2 order book maintained from market data updates.
+//!
+//! Prices and quantities are stored as the strings received from
+//! Gemini. Levels are ordered by comparing the decimal strings
+//! exactly, so no precision is lost keeping the book. Only the derived
+//! statistics (mid, spread, VWAP) are computed as `f64`.
+use crate::market_data::MarketDataEvent;
+use crate::matching::EPSILON;
+use crate::structs::order::OrderSide;
+use crate::structs::wsfeed::{Level2, Level2Change, MarketDataMessage};
+use crate::types::{GError, Result};
+use futures::{Stream, StreamExt};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Non-negative decimal price, ordered numerically without parsing.
+#[derive(Debug, Clone)]
+pub struct Price(String);
+
+impl Price {
+    pub fn new(price: &str) -> Price {
+        Price(price.to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Split into an integer part without leading zeros and a
+    /// fractional part without trailing zeros.
+    fn parts(&self) -> (&str, &str) {
+        let (int, frac) = match self.0.find('.') {
+            Some(i) => (&self.0[..i], &self.0[i + 1..]),
+            None => (self.0.as_str(), ""),
+        };
+        (int.trim_start_matches('0'), frac.trim_end_matches('0'))
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (ai, af) = self.parts();
+        let (bi, bf) = other.parts();
+        ai.len()
+            .cmp(&bi.len())
+            .then_with(|| ai.cmp(bi))
+            .then_with(|| af.cmp(bf))
+    }
+}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Price {}
+
+/// Return true if the decimal string represents zero.
+pub(crate) fn is_zero(quantity: &str) -> bool {
+    quantity.chars().all(|c| c == '0' || c == '.')
+}
+
+/// Single price level of the book as (price, quantity).
+pub type Level<'a> = (&'a str, &'a str);
+
+#[derive(Debug, Clone)]
+pub struct OrderBook {
+    symbol: String,
+    bids: BTreeMap<Price, String>,
+    asks: BTreeMap<Price, String>,
+}
+
+impl OrderBook {
+    pub fn new(symbol: &str) -> OrderBook {
+        OrderBook {
+            symbol: symbol.to_string(),
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+        }
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    /// Remove every level, e.g. before applying a fresh snapshot.
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    /// Apply a single change. A zero quantity removes the level.
+    pub fn apply_change(&mut self, change: &Level2Change) {
+        let side = match change.order {
+            OrderSide::Buy => &mut self.bids,
+            OrderSide::Sell => &mut self.asks,
+        };
+        let price = Price::new(&change.price);
+        if is_zero(&change.quantity) {
+            side.remove(&price);
+        } else {
+            side.insert(price, change.quantity.clone());
+        }
+    }
+
+    /// Apply the changes in an `l2_updates` message. The first message
+    /// after subscribing is the snapshot of the full book.
+    pub fn apply(&mut self, l2: &Level2) {
+        for change in l2.changes.iter().flatten() {
+            self.apply_change(change);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
+    /// Highest bid.
+    pub fn best_bid(&self) -> Option<Level<'_>> {
+        self.bids
+            .iter()
+            .next_back()
+            .map(|(p, q)| (p.as_str(), q.as_str()))
+    }
+
+    /// Lowest ask.
+    pub fn best_ask(&self) -> Option<Level<'_>> {
+        self.asks
+            .iter()
+            .next()
+            .map(|(p, q)| (p.as_str(), q.as_str()))
+    }
+
+    /// Levels on one side of the book, best price first.
+    pub fn levels(&self, side: OrderSide) -> Box<dyn Iterator<Item = Level<'_>> + '_> {
+        match side {
+            OrderSide::Buy => Box::new(
+                self.bids
+                    .iter()
+                    .rev()
+                    .map(|(p, q)| (p.as_str(), q.as_str())),
+            ),
+            OrderSide::Sell => Box::new(self.asks.iter().map(|(p, q)| (p.as_str(), q.as_str()))),
+        }
+    }
+
+    /// The best `n` levels of each side, as (bids, asks).
+    pub fn depth(&self, n: usize) -> (Vec<Level<'_>>, Vec<Level<'_>>) {
+        (
+            self.levels(OrderSide::Buy).take(n).collect(),
+            self.levels(OrderSide::Sell).take(n).collect(),
+        )
+    }
+
+    fn best_prices(&self) -> Option<(f64, f64)> {
+        let bid = self.best_bid()?.0.parse::<f64>().ok()?;
+        let ask = self.best_ask()?.0.parse::<f64>().ok()?;
+        Some((bid, ask))
+    }
+
+    /// Midpoint between the best bid and ask.
+    pub fn mid(&self) -> Option<f64> {
+        self.best_prices().map(|(bid, ask)| (bid + ask) / 2.0)
+    }
+
+    /// Difference between the best ask and bid.
+    pub fn spread(&self) -> Option<f64> {
+        self.best_prices().map(|(bid, ask)| ask - bid)
+    }
+
+    /// Volume-weighted average price of an order of `size` on `side`
+    /// taking liquidity from the book, i.e. a buy walks the asks.
+    ///
+    /// Returns `None` if the book is not deep enough to fill `size`.
+    pub fn vwap(&self, side: OrderSide, size: f64) -> Option<f64> {
+        if size <= 0.0 {
+            return None;
+        }
+        let opposite = match side {
+            OrderSide::Buy => OrderSide::Sell,
+            OrderSide::Sell => OrderSide::Buy,
+        };
+        let mut remaining = size;
+        let mut notional = 0.0;
+        for (price, quantity) in self.levels(opposite) {
+            let price = price.parse::<f64>().ok()?;
+            let quantity = quantity.parse::<f64>().ok()?;
+            let take = quantity.min(remaining);
+            notional += take * price;
+            remaining -= take;
+            if remaining <= EPSILON * size {
+                return Some(notional / size);
+            }
+        }
+        None
+    }
+}
+
+/// What an `OrderBookStream` does with an item of its underlying
+/// stream.
+#[derive(Debug)]
+pub enum BookInput {
+    /// Apply the changes to the book of the symbol.
+    Update(Level2),
+
+    /// Discard all books, as the next update for each symbol is a
+    /// fresh snapshot.
+    Reset,
+
+    Error(GError),
+    Ignore,
+}
+
+/// Item of a stream that an `OrderBookStream` can maintain books from.
+pub trait IntoBookInput {
+    fn into_book_input(self) -> BookInput;
+}
+
+impl IntoBookInput for MarketDataMessage {
+    fn into_book_input(self) -> BookInput {
+        match self {
+            MarketDataMessage::Level2(l2) => BookInput::Update(l2),
+            MarketDataMessage::InternalError(e) => BookInput::Error(e),
+            _ => BookInput::Ignore,
+        }
+    }
+}
+
+impl IntoBookInput for MarketDataEvent {
+    fn into_book_input(self) -> BookInput {
+        match self {
+            MarketDataEvent::Message(msg) => msg.into_book_input(),
+            MarketDataEvent::SnapshotReset => BookInput::Reset,
+            _ => BookInput::Ignore,
+        }
+    }
+}
+
+/// Stream adapter that maintains an `OrderBook` per symbol and yields
+/// the symbol after every `Level2` update. Read the updated book with
+/// `OrderBookStream::book`.
+///
+/// Works on the raw market data feed as well as on a
+/// `MarketDataClient`, in which case books are discarded on every
+/// `MarketDataEvent::SnapshotReset` and rebuilt from the snapshot that
+/// follows a reconnection. Other items are dropped; decoding errors
+/// are passed through.
+pub struct OrderBookStream<S> {
+    stream: S,
+    books: HashMap<String, OrderBook>,
+}
+
+impl<S> OrderBookStream<S> {
+    pub fn new(stream: S) -> Self {
+        OrderBookStream {
+            stream,
+            books: HashMap::new(),
+        }
+    }
+
+    /// Current book for a symbol.
+    pub fn book(&self, symbol: &str) -> Option<&OrderBook> {
+        self.books.get(symbol)
+    }
+
+    /// Discard all books, e.g. after reconnecting when a new snapshot
+    /// will be received.
+    pub fn reset(&mut self) {
+        self.books.clear();
+    }
+}
+
+impl<S, A> Stream for OrderBookStream<S>
+where
+    S: Stream<Item = Result<A>> + Unpin,
+    A: IntoBookInput,
+{
+    type Item = Result<String>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let item = match futures::ready!(self.stream.poll_next_unpin(cx)) {
+                Some(Ok(item)) => item,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            };
+            match item.into_book_input() {
+                BookInput::Update(l2) => {
+                    self.books
+                        .entry(l2.symbol.clone())
+                        .or_insert_with(|| OrderBook::new(&l2.symbol))
+                        .apply(&l2);
+                    return Poll::Ready(Some(Ok(l2.symbol)));
+                }
+                BookInput::Reset => self.reset(),
+                BookInput::Error(e) => return Poll::Ready(Some(Err(e))),
+                BookInput::Ignore => continue,
+            }
+        }
+    }
+}
+
+/// Convert a market data stream, or a `MarketDataClient`, into a
+/// stream of updated order books.
+pub fn order_books<S>(stream: S) -> OrderBookStream<S> {
+    OrderBookStream::new(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(side: OrderSide, price: &str, quantity: &str) -> Level2Change {
+        Level2Change {
+            order: side,
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+        }
+    }
+
+    fn level2(symbol: &str, changes: &[(OrderSide, &str, &str)]) -> Level2 {
+        Level2 {
+            symbol: symbol.to_string(),
+            changes: Some(changes.iter().map(|(s, p, q)| change(*s, p, q)).collect()),
+            trades: None,
+            auction_events: None,
+        }
+    }
+
+    #[test]
+    fn price_ordering() {
+        let p = Price::new;
+        assert!(p("9.99") < p("10"));
+        assert!(p("10") < p("10.01"));
+        assert!(p("100.5") > p("100.49999"));
+        assert!(p("0.001") < p("0.01"));
+        assert!(p(".5") > p("0.49"));
+        assert_eq!(p("10.50"), p("10.5"));
+        assert_eq!(p("010"), p("10.000"));
+        assert_eq!(p("0"), p("0.00"));
+
+        let mut prices: Vec<Price> = ["2", "10.1", "0.5", "10.05", "1000", "99.999"]
+            .iter()
+            .map(|s| p(s))
+            .collect();
+        prices.sort();
+        let sorted: Vec<&str> = prices.iter().map(Price::as_str).collect();
+        assert_eq!(sorted, ["0.5", "2", "10.05", "10.1", "99.999", "1000"]);
+    }
+
+    #[test]
+    fn zero_quantities() {
+        assert!(is_zero("0"));
+        assert!(is_zero("0.000"));
+        assert!(!is_zero("0.001"));
+    }
+
+    #[test]
+    fn book_levels_and_statistics() {
+        let mut book = OrderBook::new("BTCUSD");
+        book.apply(&level2(
+            "BTCUSD",
+            &[
+                (OrderSide::Buy, "99.50", "2"),
+                (OrderSide::Buy, "100.00", "1"),
+                (OrderSide::Sell, "101.00", "1"),
+                (OrderSide::Sell, "102.00", "3"),
+            ],
+        ));
+        assert_eq!(book.best_bid(), Some(("100.00", "1")));
+        assert_eq!(book.best_ask(), Some(("101.00", "1")));
+        assert_eq!(book.mid(), Some(100.5));
+        assert_eq!(book.spread(), Some(1.0));
+        assert_eq!(book.vwap(OrderSide::Buy, 2.0), Some(101.5));
+        assert_eq!(
+            book.vwap(OrderSide::Sell, 3.0),
+            Some((100.0 + 2.0 * 99.5) / 3.0)
+        );
+        assert_eq!(book.vwap(OrderSide::Buy, 5.0), None);
+
+        book.apply_change(&change(OrderSide::Buy, "100.0", "0"));
+        book.apply_change(&change(OrderSide::Sell, "101", "0.5"));
+        assert_eq!(book.best_bid(), Some(("99.50", "2")));
+        assert_eq!(book.best_ask().unwrap().1, "0.5");
+        let (bids, asks) = book.depth(5);
+        assert_eq!((bids.len(), asks.len()), (1, 2));
+    }
+
+    #[test]
+    fn vwap_ignores_float_residue() {
+        let mut book = OrderBook::new("BTCUSD");
+        book.apply(&level2(
+            "BTCUSD",
+            &[
+                (OrderSide::Sell, "101.00", "0.1"),
+                (OrderSide::Sell, "102.00", "0.2"),
+            ],
+        ));
+        // 0.1 + 0.2 is slightly more than 0.3.
+        let vwap = book.vwap(OrderSide::Buy, 0.1 + 0.2).unwrap();
+        assert!((vwap - (10.1 + 20.4) / 0.3).abs() < 1e-9);
+        assert_eq!(book.vwap(OrderSide::Buy, 0.31), None);
+    }
+
+    #[tokio::test]
+    async fn stream_yields_updated_symbols() {
+        let messages = vec![
+            Ok(MarketDataMessage::Level2(level2(
+                "BTCUSD",
+                &[(OrderSide::Buy, "100", "1"), (OrderSide::Sell, "101", "1")],
+            ))),
+            Ok(MarketDataMessage::Level2(level2(
+                "ETHUSD",
+                &[(OrderSide::Buy, "10", "1")],
+            ))),
+            Ok(MarketDataMessage::Level2(level2(
+                "BTCUSD",
+                &[(OrderSide::Sell, "100.5", "2")],
+            ))),
+        ];
+        let mut stream = order_books(futures::stream::iter(messages));
+        let mut symbols = Vec::new();
+        while let Some(symbol) = stream.next().await {
+            symbols.push(symbol.unwrap());
+        }
+        assert_eq!(symbols, ["BTCUSD", "ETHUSD", "BTCUSD"]);
+        let book = stream.book("BTCUSD").unwrap();
+        assert_eq!(book.best_ask(), Some(("100.5", "2")));
+        assert_eq!(stream.book("ETHUSD").unwrap().mid(), None);
+    }
+
+    #[tokio::test]
+    async fn snapshot_reset_discards_stale_levels() {
+        let update = |changes: &[(OrderSide, &str, &str)]| {
+            Ok(MarketDataEvent::Message(MarketDataMessage::Level2(level2(
+                "BTCUSD", changes,
+            ))))
+        };
+        let events = vec![
+            update(&[(OrderSide::Buy, "100", "1"), (OrderSide::Sell, "101", "1")]),
+            Ok(MarketDataEvent::Disconnected(None)),
+            Ok(MarketDataEvent::Reconnected { attempts: 1 }),
+            Ok(MarketDataEvent::SnapshotReset),
+            update(&[(OrderSide::Buy, "99", "2"), (OrderSide::Sell, "101", "1")]),
+        ];
+        let mut stream = order_books(futures::stream::iter(events));
+        let mut updates = 0;
+        while let Some(symbol) = stream.next().await {
+            assert_eq!(symbol.unwrap(), "BTCUSD");
+            updates += 1;
+        }
+        assert_eq!(updates, 2);
+        let (bids, _) = stream.book("BTCUSD").unwrap().depth(10);
+        assert_eq!(bids, [("99", "2")]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Emergency stop for a `Private` client.
+//!
+//! Every `Private` client owns a `KillSwitch`. Once engaged, through
+//! `Private::kill` or by triggering a handle from another task, the
+//! client refuses new orders with `GError::KillSwitchEngaged` until the
+//! switch is reset by hand.
+use crate::structs::order::OrderId;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Latch that blocks new orders. Clones share the same latch.
+#[derive(Debug, Clone, Default)]
+pub struct KillSwitch {
+    engaged: Arc<AtomicBool>,
+}
+
+impl KillSwitch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Block new orders. This does not cancel open orders; use
+    /// `Private::kill` for that.
+    pub fn trigger(&self) {
+        self.engaged.store(true, Ordering::SeqCst);
+    }
+
+    /// Allow new orders again.
+    pub fn reset(&self) {
+        self.engaged.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged.load(Ordering::SeqCst)
+    }
+}
+
+/// Outcome of `Private::kill`.
+#[derive(Debug, Clone, Default)]
+pub struct KillReport {
+    /// Orders cancelled, either by `cancel_all_orders` or by a retry.
+    pub cancelled: Vec<OrderId>,
+
+    /// Orders that could not be cancelled because they had closed in
+    /// the meantime, typically by filling.
+    pub filled: Vec<OrderId>,
+
+    /// Orders still not cancelled after every retry.
+    pub failed: Vec<OrderId>,
+}
+
+impl KillReport {
+    /// Whether every order was cancelled.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::MockServer;
+    use crate::private::Private;
+    use crate::structs::order::OrderId;
+    use crate::structs::{OrderBuilder, OrderSide};
+    use crate::types::GError;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    async fn client() -> (MockServer, Private) {
+        let server = MockServer::start("key", "secret").await.unwrap();
+        let private = Private::new(&server.uri(), "key", "secret");
+        (server, private)
+    }
+
+    fn cancel_response(order_id: u64, is_live: bool, is_cancelled: bool) -> String {
+        json!({
+            "order_id": order_id.to_string(),
+            "symbol": "btcusd",
+            "exchange": "gemini",
+            "price": "100.00",
+            "avg_execution_price": "100.00",
+            "side": "buy",
+            "type": "exchange limit",
+            "options": [],
+            "is_live": is_live,
+            "is_cancelled": is_cancelled,
+            "executed_amount": "1",
+            "remaining_amount": "0",
+            "original_amount": "1",
+            "is_hidden": false,
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn kill_cancels_and_blocks_new_orders() {
+        let (server, private) = client().await;
+        let order = OrderBuilder::limit("btcusd", OrderSide::Buy, 1.0, 100.0)
+            .build()
+            .unwrap();
+        private.new_order(&order).await.unwrap();
+        private.new_order(&order).await.unwrap();
+
+        let report = private.kill().await.unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.cancelled.len(), 2);
+        assert!(server.open_orders().is_empty());
+        assert!(matches!(
+            private.new_order(&order).await,
+            Err(GError::KillSwitchEngaged)
+        ));
+
+        private.kill_switch().reset();
+        private.new_order(&order).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn kill_retries_failed_cancel_all() {
+        let (server, private) = client().await;
+        let private = Arc::new(private);
+        server.error("/v1/order/cancel/all", 503, "ServiceUnavailable", "");
+
+        let kill = tokio::spawn({
+            let private = private.clone();
+            async move { private.kill().await }
+        });
+        while server.requests().is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        server.clear_fixture("/v1/order/cancel/all");
+
+        let report = kill.await.unwrap().unwrap();
+        assert!(report.is_complete());
+        assert!(server.requests().len() >= 2);
+    }
+
+    #[tokio::test]
+    async fn kill_reports_filled_orders_separately() {
+        let (server, private) = client().await;
+        let cancel_all = json!({
+            "result": "ok",
+            "details": { "cancelRejects": [7, 8], "cancelledOrders": [6] },
+        });
+        server.fixture("/v1/order/cancel/all", &cancel_all.to_string());
+        server.fixture("/v1/order/cancel", &cancel_response(7, false, false));
+
+        let report = private.kill().await.unwrap();
+        assert_eq!(report.cancelled, vec![OrderId(6)]);
+        assert_eq!(report.filled, vec![OrderId(7), OrderId(8)]);
+        assert!(report.is_complete());
+    }
+}
@@ -1,8 +1,25 @@
+pub mod backtest;
+pub mod balances;
+pub mod book;
+pub mod kill_switch;
+pub mod market_data;
+mod matching;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod order_manager;
+pub mod paper;
+pub mod pnl;
 pub mod private;
 pub mod public;
+pub mod recorder;
+pub mod replay;
+pub mod risk;
+pub mod sequence;
 pub mod structs;
+pub mod trading;
 pub mod types;
 mod util;
+pub mod watchdog;
 pub mod wsfeed;
 
 pub use private::Private;
@@ -0,0 +1,562 @@
+//! Market data client that survives dropped websocket connections.
+//!
+//! `MarketDataClient` owns the connection in a background task. When
+//! the socket fails or closes, the task reconnects with exponential
+//! backoff and re-sends the original subscriptions. Consumers see
+//! explicit `Disconnected`, `Reconnected` and `SnapshotReset` events,
+//! so that local state such as an `OrderBook` can be rebuilt from the
+//! fresh snapshot. With `ReconnectConfig::stale_timeout` set, a silent
+//! connection is treated as failed and replaced as well.
+//!
+//! Subscriptions can be changed on the live connection through a
+//! `MarketDataHandle`; the client keeps track of the active set and
+//! restores it after reconnecting.
+use crate::structs::wsfeed::MarketDataMessage;
+use crate::types::{GError, Result};
+use crate::watchdog::WatchdogExt;
+use crate::wsfeed::{Subscribe, Subscription, SubscriptionType, WSFeed};
+use futures::stream::BoxStream;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message as TMessage;
+
+/// Backoff policy used when reconnecting.
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    /// Delay after the first failed reconnection attempt.
+    pub initial_backoff: Duration,
+
+    /// Upper bound on the delay between attempts.
+    pub max_backoff: Duration,
+
+    /// Factor applied to the delay after each failed attempt.
+    pub multiplier: f64,
+
+    /// Give up after this many consecutive failed attempts.
+    /// `None` retries forever.
+    pub max_attempts: Option<u32>,
+
+    /// Reconnect if no message, including heartbeats, arrives within
+    /// this duration.
+    pub stale_timeout: Option<Duration>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            max_attempts: None,
+            stale_timeout: None,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    fn next_backoff(&self, backoff: Duration) -> Duration {
+        backoff.mul_f64(self.multiplier).min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Reconnecting,
+    Closed,
+}
+
+/// Counters describing the health of the connection.
+#[derive(Debug)]
+pub struct ConnectionMetrics {
+    connects: AtomicU64,
+    disconnects: AtomicU64,
+    reconnect_attempts: AtomicU64,
+    messages: AtomicU64,
+    state: Mutex<ConnectionState>,
+    last_connected: Mutex<Option<Instant>>,
+}
+
+impl ConnectionMetrics {
+    fn new() -> Self {
+        ConnectionMetrics {
+            connects: AtomicU64::new(0),
+            disconnects: AtomicU64::new(0),
+            reconnect_attempts: AtomicU64::new(0),
+            messages: AtomicU64::new(0),
+            state: Mutex::new(ConnectionState::Reconnecting),
+            last_connected: Mutex::new(None),
+        }
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    fn on_connect(&self) {
+        self.connects.fetch_add(1, Ordering::Relaxed);
+        *self.last_connected.lock().unwrap() = Some(Instant::now());
+        self.set_state(ConnectionState::Connected);
+    }
+
+    fn on_disconnect(&self) {
+        self.disconnects.fetch_add(1, Ordering::Relaxed);
+        self.set_state(ConnectionState::Reconnecting);
+    }
+
+    /// Number of successful connections, including the first.
+    pub fn connects(&self) -> u64 {
+        self.connects.load(Ordering::Relaxed)
+    }
+
+    /// Number of times an established connection was lost.
+    pub fn disconnects(&self) -> u64 {
+        self.disconnects.load(Ordering::Relaxed)
+    }
+
+    /// Total number of reconnection attempts, successful or not.
+    pub fn reconnect_attempts(&self) -> u64 {
+        self.reconnect_attempts.load(Ordering::Relaxed)
+    }
+
+    /// Number of market data messages received.
+    pub fn messages(&self) -> u64 {
+        self.messages.load(Ordering::Relaxed)
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Time at which the current (or last) connection was established.
+    pub fn last_connected(&self) -> Option<Instant> {
+        *self.last_connected.lock().unwrap()
+    }
+}
+
+/// Item produced by `MarketDataClient`.
+#[derive(Debug)]
+pub enum MarketDataEvent {
+    Message(MarketDataMessage),
+
+    /// The connection was lost, with the error that caused it if any.
+    Disconnected(Option<GError>),
+
+    /// A new connection was established and subscriptions re-sent.
+    Reconnected {
+        attempts: u32,
+    },
+
+    /// All previously received book state is stale. The next `Level2`
+    /// message for each symbol is a full snapshot. `OrderBookStream`
+    /// discards its books when it sees this event.
+    SnapshotReset,
+}
+
+type EventSender = mpsc::UnboundedSender<Result<MarketDataEvent>>;
+type MessageStream = BoxStream<'static, Result<MarketDataMessage>>;
+type MessageSink = Pin<Box<dyn Sink<TMessage, Error = GError> + Send>>;
+
+async fn connect(
+    uri: &str,
+    subscriptions: &[Subscription],
+    config: &ReconnectConfig,
+) -> Result<(MessageSink, MessageStream)> {
+    let (sink, stream) = WSFeed::connect_public_data(uri, subscriptions)
+        .await?
+        .split();
+    let stream = match config.stale_timeout {
+        Some(timeout) => stream.watchdog(timeout).boxed(),
+        None => stream.boxed(),
+    };
+    Ok((Box::pin(sink), stream))
+}
+
+/// Set of active subscriptions, keyed by subscription type.
+#[derive(Debug, Default)]
+struct ActiveSubscriptions(HashMap<SubscriptionType, Vec<String>>);
+
+impl ActiveSubscriptions {
+    fn add(&mut self, subscriptions: &[Subscription]) {
+        for sub in subscriptions {
+            let symbols = self.0.entry(sub.name).or_default();
+            for symbol in &sub.symbols {
+                if !symbols.contains(symbol) {
+                    symbols.push(symbol.clone());
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, subscriptions: &[Subscription]) {
+        for sub in subscriptions {
+            if let Some(symbols) = self.0.get_mut(&sub.name) {
+                symbols.retain(|s| !sub.symbols.contains(s));
+                if symbols.is_empty() {
+                    self.0.remove(&sub.name);
+                }
+            }
+        }
+    }
+
+    fn to_vec(&self) -> Vec<Subscription> {
+        self.0
+            .iter()
+            .map(|(name, symbols)| Subscription {
+                name: *name,
+                symbols: symbols.clone(),
+            })
+            .collect()
+    }
+}
+
+enum Command {
+    Subscribe(Vec<Subscription>, oneshot::Sender<Result<()>>),
+    Unsubscribe(Vec<Subscription>, oneshot::Sender<Result<()>>),
+}
+
+/// Cloneable handle for changing the subscriptions of a
+/// `MarketDataClient` while its stream is consumed elsewhere.
+#[derive(Clone)]
+pub struct MarketDataHandle {
+    commands: mpsc::UnboundedSender<Command>,
+    active: Arc<Mutex<ActiveSubscriptions>>,
+}
+
+impl MarketDataHandle {
+    async fn send(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<()>>) -> Command,
+    ) -> Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .map_err(|_| GError::ConnectionClosed)?;
+        response.await.map_err(|_| GError::ConnectionClosed)?
+    }
+
+    /// Subscribe to additional channels on the live connection.
+    ///
+    /// The subscriptions are remembered even if sending fails, and are
+    /// restored on the next reconnection.
+    pub async fn subscribe(&self, subscriptions: &[Subscription]) -> Result<()> {
+        let subscriptions = subscriptions.to_vec();
+        self.send(|reply| Command::Subscribe(subscriptions, reply))
+            .await
+    }
+
+    /// Unsubscribe from channels on the live connection.
+    pub async fn unsubscribe(&self, subscriptions: &[Subscription]) -> Result<()> {
+        let subscriptions = subscriptions.to_vec();
+        self.send(|reply| Command::Unsubscribe(subscriptions, reply))
+            .await
+    }
+
+    /// Currently active subscriptions.
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        self.active.lock().unwrap().to_vec()
+    }
+}
+
+/// Reconnecting market data connection.
+///
+/// Yields `MarketDataEvent`s until dropped, or until reconnection is
+/// abandoned per `ReconnectConfig::max_attempts`, in which case the
+/// last connection error is yielded before the stream ends.
+pub struct MarketDataClient {
+    events: mpsc::UnboundedReceiver<Result<MarketDataEvent>>,
+    handle: MarketDataHandle,
+    metrics: Arc<ConnectionMetrics>,
+    task: JoinHandle<()>,
+}
+
+impl MarketDataClient {
+    /// Connect and subscribe. Fails if the initial connection cannot
+    /// be established; later failures are retried.
+    pub async fn connect(
+        uri: &str,
+        subscriptions: &[Subscription],
+        config: ReconnectConfig,
+    ) -> Result<MarketDataClient> {
+        let connection = connect(uri, subscriptions, &config).await?;
+
+        let metrics = Arc::new(ConnectionMetrics::new());
+        metrics.on_connect();
+
+        let mut active = ActiveSubscriptions::default();
+        active.add(subscriptions);
+        let active = Arc::new(Mutex::new(active));
+
+        let (tx, events) = mpsc::unbounded_channel();
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(run(
+            uri.to_string(),
+            config,
+            connection,
+            tx,
+            command_rx,
+            active.clone(),
+            metrics.clone(),
+        ));
+
+        Ok(MarketDataClient {
+            events,
+            handle: MarketDataHandle { commands, active },
+            metrics,
+            task,
+        })
+    }
+
+    pub fn metrics(&self) -> Arc<ConnectionMetrics> {
+        self.metrics.clone()
+    }
+
+    pub fn handle(&self) -> MarketDataHandle {
+        self.handle.clone()
+    }
+
+    /// See `MarketDataHandle::subscribe`.
+    pub async fn subscribe(&self, subscriptions: &[Subscription]) -> Result<()> {
+        self.handle.subscribe(subscriptions).await
+    }
+
+    /// See `MarketDataHandle::unsubscribe`.
+    pub async fn unsubscribe(&self, subscriptions: &[Subscription]) -> Result<()> {
+        self.handle.unsubscribe(subscriptions).await
+    }
+
+    /// Currently active subscriptions.
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        self.handle.subscriptions()
+    }
+}
+
+impl Drop for MarketDataClient {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.metrics.set_state(ConnectionState::Closed);
+    }
+}
+
+impl Stream for MarketDataClient {
+    type Item = Result<MarketDataEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+/// Apply a subscription change to the active set and forward it on the
+/// connection. Returns false if the connection failed.
+async fn execute(
+    command: Command,
+    sink: &mut MessageSink,
+    active: &Mutex<ActiveSubscriptions>,
+) -> bool {
+    let (msg, reply) = match command {
+        Command::Subscribe(subs, reply) => {
+            active.lock().unwrap().add(&subs);
+            (Subscribe::subscribe_to(&subs), reply)
+        }
+        Command::Unsubscribe(subs, reply) => {
+            active.lock().unwrap().remove(&subs);
+            (Subscribe::unsubscribe_from(&subs), reply)
+        }
+    };
+    let result = match msg.to_message() {
+        Ok(msg) => sink.send(msg).await,
+        Err(e) => Err(e),
+    };
+    let connected = !matches!(result, Err(GError::Websocket(_)));
+    let _ = reply.send(result);
+    connected
+}
+
+async fn run(
+    uri: String,
+    config: ReconnectConfig,
+    connection: (MessageSink, MessageStream),
+    tx: EventSender,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    active: Arc<Mutex<ActiveSubscriptions>>,
+    metrics: Arc<ConnectionMetrics>,
+) {
+    let (mut sink, mut stream) = connection;
+    loop {
+        let error = loop {
+            tokio::select! {
+                msg = stream.next() => match msg {
+                    Some(Ok(msg)) => {
+                        metrics.messages.fetch_add(1, Ordering::Relaxed);
+                        if tx.send(Ok(MarketDataEvent::Message(msg))).is_err() {
+                            return;
+                        }
+                    }
+                    Some(Err(e)) => break Some(e),
+                    None => break None,
+                },
+                command = commands.recv() => match command {
+                    Some(command) => {
+                        if !execute(command, &mut sink, &active).await {
+                            break None;
+                        }
+                    }
+                    None => return,
+                },
+            }
+        };
+
+        metrics.on_disconnect();
+        if tx.send(Ok(MarketDataEvent::Disconnected(error))).is_err() {
+            return;
+        }
+
+        let mut attempts = 0;
+        let mut backoff = config.initial_backoff;
+        let connection = loop {
+            attempts += 1;
+            metrics.reconnect_attempts.fetch_add(1, Ordering::Relaxed);
+            let subscriptions = active.lock().unwrap().to_vec();
+            match connect(&uri, &subscriptions, &config).await {
+                Ok(c) => break c,
+                Err(e) => {
+                    if matches!(config.max_attempts, Some(max) if attempts >= max) {
+                        metrics.set_state(ConnectionState::Closed);
+                        let _ = tx.send(Err(e));
+                        return;
+                    }
+                    tokio::time::sleep(backoff).await;
+                    backoff = config.next_backoff(backoff);
+                }
+            }
+        };
+        sink = connection.0;
+        stream = connection.1;
+
+        metrics.on_connect();
+        let reconnected = tx.send(Ok(MarketDataEvent::Reconnected { attempts }));
+        if reconnected.is_err() || tx.send(Ok(MarketDataEvent::SnapshotReset)).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sub(name: SubscriptionType, symbols: &[&str]) -> Subscription {
+        Subscription {
+            name,
+            symbols: symbols.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn symbols(active: &ActiveSubscriptions, name: SubscriptionType) -> Option<Vec<&str>> {
+        let symbols = active.0.get(&name)?;
+        Some(symbols.iter().map(|s| s.as_str()).collect())
+    }
+
+    #[test]
+    fn active_subscriptions_bookkeeping() {
+        let mut active = ActiveSubscriptions::default();
+        active.add(&[sub(SubscriptionType::L2, &["BTCUSD", "ETHUSD"])]);
+        active.add(&[
+            sub(SubscriptionType::L2, &["ETHUSD", "SOLUSD"]),
+            sub(SubscriptionType::MarkPrice, &["BTCGUSDPERP"]),
+        ]);
+        assert_eq!(
+            symbols(&active, SubscriptionType::L2),
+            Some(vec!["BTCUSD", "ETHUSD", "SOLUSD"])
+        );
+        assert_eq!(
+            symbols(&active, SubscriptionType::MarkPrice),
+            Some(vec!["BTCGUSDPERP"])
+        );
+
+        active.remove(&[
+            sub(SubscriptionType::L2, &["ETHUSD", "LTCUSD"]),
+            sub(SubscriptionType::MarkPrice, &["BTCGUSDPERP"]),
+            sub(SubscriptionType::FundingAmount, &["BTCGUSDPERP"]),
+        ]);
+        assert_eq!(
+            symbols(&active, SubscriptionType::L2),
+            Some(vec!["BTCUSD", "SOLUSD"])
+        );
+        assert_eq!(symbols(&active, SubscriptionType::MarkPrice), None);
+
+        let subscriptions = active.to_vec();
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(subscriptions[0].name, SubscriptionType::L2);
+        assert_eq!(subscriptions[0].symbols, vec!["BTCUSD", "SOLUSD"]);
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_maximum() {
+        let config = ReconnectConfig::default();
+        let mut backoff = config.initial_backoff;
+        let mut schedule = Vec::new();
+        for _ in 0..8 {
+            schedule.push(backoff.as_millis());
+            backoff = config.next_backoff(backoff);
+        }
+        assert_eq!(
+            schedule,
+            vec![500, 1000, 2000, 4000, 8000, 16000, 30000, 30000]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnects_with_backoff_until_max_attempts() {
+        // Nothing listens on the port once the listener is dropped, so
+        // every reconnection attempt is refused.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let config = ReconnectConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 3.0,
+            max_attempts: Some(5),
+            stale_timeout: None,
+        };
+        let sink: MessageSink = Box::pin(futures::sink::drain().sink_map_err(|e| match e {}));
+        let stream: MessageStream = futures::stream::empty().boxed();
+        let (tx, mut events) = mpsc::unbounded_channel();
+        let (_commands, command_rx) = mpsc::unbounded_channel();
+        let metrics = Arc::new(ConnectionMetrics::new());
+        let active = Arc::new(Mutex::new(ActiveSubscriptions::default()));
+        let task = tokio::spawn(run(
+            uri,
+            config,
+            (sink, stream),
+            tx,
+            command_rx,
+            active,
+            metrics.clone(),
+        ));
+
+        assert!(matches!(
+            events.recv().await,
+            Some(Ok(MarketDataEvent::Disconnected(None)))
+        ));
+        let disconnected = tokio::time::Instant::now();
+        assert!(matches!(events.recv().await, Some(Err(_))));
+        // 100ms, 300ms, 900ms, then capped at 1s between five attempts.
+        assert_eq!(disconnected.elapsed(), Duration::from_millis(2300));
+        assert!(events.recv().await.is_none());
+
+        task.await.unwrap();
+        assert_eq!(metrics.reconnect_attempts(), 5);
+        assert_eq!(metrics.disconnects(), 1);
+        assert_eq!(metrics.state(), ConnectionState::Closed);
+    }
+}
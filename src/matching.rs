@@ -0,0 +1,527 @@
+//! Matching engine shared by the paper trading and backtesting
+//! simulators.
+//!
+//! A `Matcher` holds the local book of one symbol and the simulated
+//! orders resting on it, and reports what happens to them as
+//! `MatchEvent`s. It only decides fills; balances, fees and PnL are
+//! left to the simulator using it.
+//!
+//! Matching rules:
+//!  - orders that cross the book take liquidity from the opposite
+//!    levels, up to their limit price, as the taker
+//!  - resting orders fill at their own price, as the maker, when the
+//!    opposite side of the book moves through them or a trade prints
+//!    through their price
+//!  - with queue modeling, an order joins the back of its price level,
+//!    trades at that price consume the queue ahead of it before
+//!    filling it, and cancellations shrink the queue ahead to at most
+//!    the size of the level; without it, trades at the order's price
+//!    never fill it
+//!  - stop-limit orders trigger once a trade or the near side of the
+//!    book reaches the stop price
+//!
+//! Liquidity taken by simulated orders is removed from the local copy
+//! of the book until the feed updates that level again.
+use crate::book::OrderBook;
+use crate::structs::order::{
+    Order, OrderId, OrderOption, OrderResponse, OrderSide, OrderType, RejectReason,
+};
+use crate::structs::wsfeed::{
+    Fill, FillLiquidity, Level2, Level2Change, OrderEventType, OrderStatus,
+};
+use crate::types::{GError, GeminiResponseError};
+use crate::util::parse_f64;
+use std::collections::BTreeMap;
+
+/// Quantities below this are treated as zero.
+pub(crate) const EPSILON: f64 = 1e-12;
+
+pub(crate) fn opposite(side: OrderSide) -> OrderSide {
+    match side {
+        OrderSide::Buy => OrderSide::Sell,
+        OrderSide::Sell => OrderSide::Buy,
+    }
+}
+
+/// Return true if `price` is at or better than `limit` for an order
+/// on `side`.
+pub(crate) fn within(side: OrderSide, price: f64, limit: f64) -> bool {
+    match side {
+        OrderSide::Buy => price <= limit,
+        OrderSide::Sell => price >= limit,
+    }
+}
+
+/// Format a quantity without the noise of floating point arithmetic.
+pub(crate) fn decimal(x: f64) -> String {
+    let s = format!("{:.10}", x);
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+pub(crate) fn now_ms() -> u64 {
+    chrono::Utc::now().timestamp_millis() as u64
+}
+
+/// Error returned by Gemini for a request it refused.
+pub(crate) fn gemini_error(reason: RejectReason, message: String) -> GError {
+    GError::Gemini(GeminiResponseError {
+        result: "error".to_string(),
+        reason: reason.as_str().to_string(),
+        message,
+    })
+}
+
+/// Error returned by Gemini for an order refused before it was
+/// accepted. As on Gemini, no order id is assigned and no order event
+/// is sent.
+pub(crate) fn reject(order: &Order, reason: RejectReason) -> GError {
+    let message = format!(
+        "Failed to place {:?} order on symbol '{}' for price {} and quantity {}: {}",
+        order.side(),
+        order.symbol(),
+        order.price(),
+        order.amount(),
+        reason
+    );
+    gemini_error(reason, message)
+}
+
+/// Refuse orders with options the simulators do not support.
+pub(crate) fn check_options(order: &Order) -> Result<(), GError> {
+    let unsupported = order.options().iter().any(|o| {
+        matches!(
+            o,
+            OrderOption::AuctionOnly | OrderOption::IndicationOfInterest
+        )
+    });
+    if unsupported {
+        return Err(reject(order, RejectReason::InvalidOrderType));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SimOrder {
+    pub(crate) order_id: OrderId,
+    pub(crate) client_order_id: Option<String>,
+    pub(crate) symbol: String,
+    pub(crate) side: OrderSide,
+    pub(crate) order_type: OrderType,
+    pub(crate) options: Vec<OrderOption>,
+    pub(crate) price: String,
+    pub(crate) limit: f64,
+    pub(crate) amount: f64,
+    /// Stop price of a stop-limit order that has not triggered yet.
+    pub(crate) stop: Option<f64>,
+    pub(crate) executed: f64,
+    pub(crate) notional: f64,
+    pub(crate) is_live: bool,
+    pub(crate) is_cancelled: bool,
+    pub(crate) reason: Option<RejectReason>,
+}
+
+impl SimOrder {
+    pub(crate) fn new(order_id: OrderId, order: &Order) -> SimOrder {
+        let client_order_id = order.client_order_id();
+        SimOrder {
+            order_id,
+            client_order_id: if client_order_id.is_empty() {
+                None
+            } else {
+                Some(client_order_id.to_string())
+            },
+            symbol: order.symbol().to_lowercase(),
+            side: order.side(),
+            order_type: order.order_type(),
+            options: order.options().to_vec(),
+            price: order.price().to_string(),
+            limit: parse_f64(order.price()),
+            amount: parse_f64(order.amount()),
+            stop: order.stop_price().map(parse_f64),
+            executed: 0.0,
+            notional: 0.0,
+            is_live: true,
+            is_cancelled: false,
+            reason: None,
+        }
+    }
+
+    pub(crate) fn remaining(&self) -> f64 {
+        let remaining = self.amount - self.executed;
+        if remaining < EPSILON {
+            0.0
+        } else {
+            remaining
+        }
+    }
+
+    fn avg_execution_price(&self) -> String {
+        if self.executed > 0.0 {
+            decimal(self.notional / self.executed)
+        } else {
+            "0.00".to_string()
+        }
+    }
+
+    pub(crate) fn has_option(&self, option: OrderOption) -> bool {
+        self.options.contains(&option)
+    }
+
+    pub(crate) fn cancel(&mut self, reason: RejectReason) {
+        self.is_live = false;
+        self.is_cancelled = true;
+        self.reason = Some(reason);
+    }
+
+    fn fill(&mut self, price: f64, amount: f64) {
+        self.executed += amount;
+        self.notional += amount * price;
+        if self.remaining() == 0.0 {
+            self.is_live = false;
+        }
+    }
+
+    pub(crate) fn status(&self, event_type: OrderEventType, fill: Option<Fill>) -> OrderStatus {
+        OrderStatus {
+            event_type,
+            order_id: self.order_id,
+            client_order_id: self.client_order_id.clone(),
+            event_id: None,
+            api_session: None,
+            symbol: self.symbol.clone(),
+            side: self.side,
+            behavior: self.options.first().copied(),
+            order_type: self.order_type.as_str().to_string(),
+            timestampms: now_ms(),
+            is_live: self.is_live,
+            is_cancelled: self.is_cancelled,
+            is_hidden: false,
+            avg_execution_price: self.avg_execution_price(),
+            executed_amount: decimal(self.executed),
+            remaining_amount: decimal(self.remaining()),
+            original_amount: decimal(self.amount),
+            price: self.price.clone(),
+            total_spend: None,
+            reason: self.reason.clone(),
+            fill,
+            socket_sequence: None,
+        }
+    }
+
+    pub(crate) fn response(&self) -> OrderResponse {
+        OrderResponse {
+            order_id: self.order_id,
+            client_order_id: self.client_order_id.clone(),
+            symbol: self.symbol.clone(),
+            exchange: "gemini".to_string(),
+            price: self.price.clone(),
+            avg_execution_price: self.avg_execution_price(),
+            side: self.side,
+            order_type: self.order_type.as_str().to_string(),
+            options: self.options.clone(),
+            is_live: self.is_live,
+            is_cancelled: self.is_cancelled,
+            reason: self.reason.clone(),
+            executed_amount: decimal(self.executed),
+            remaining_amount: decimal(self.remaining()),
+            original_amount: decimal(self.amount),
+            is_hidden: false,
+        }
+    }
+}
+
+/// Change to a simulated order. Each event carries the order as it is
+/// right after the change.
+#[derive(Debug, Clone)]
+pub(crate) enum MatchEvent {
+    Fill {
+        order: SimOrder,
+        price: String,
+        amount: f64,
+        liquidity: FillLiquidity,
+    },
+    Booked(SimOrder),
+    Cancelled(SimOrder),
+    Closed(SimOrder),
+}
+
+#[derive(Debug, Clone)]
+struct Resting {
+    order: SimOrder,
+    /// Quantity ahead of the order in its price level's queue, when
+    /// queue position is modeled.
+    ahead: Option<f64>,
+}
+
+/// Local book and resting simulated orders of a single symbol.
+#[derive(Debug, Clone)]
+pub(crate) struct Matcher {
+    book: OrderBook,
+    orders: BTreeMap<OrderId, Resting>,
+    queue: bool,
+}
+
+impl Matcher {
+    /// Matcher for `symbol`, modeling queue position if `queue` is
+    /// true.
+    pub(crate) fn new(symbol: &str, queue: bool) -> Matcher {
+        Matcher {
+            book: OrderBook::new(symbol),
+            orders: BTreeMap::new(),
+            queue,
+        }
+    }
+
+    pub(crate) fn book(&self) -> &OrderBook {
+        &self.book
+    }
+
+    /// Resting orders, oldest first.
+    pub(crate) fn orders(&self) -> impl Iterator<Item = &SimOrder> {
+        self.orders.values().map(|r| &r.order)
+    }
+
+    pub(crate) fn contains(&self, order_id: OrderId) -> bool {
+        self.orders.contains_key(&order_id)
+    }
+
+    /// Quantity ahead of a resting order in its price level's queue,
+    /// when queue position is modeled.
+    pub(crate) fn queue_ahead(&self, order_id: OrderId) -> Option<f64> {
+        self.orders.get(&order_id)?.ahead
+    }
+
+    fn level_quantity(&self, side: OrderSide, price: f64) -> f64 {
+        self.book
+            .levels(side)
+            .find(|(p, _)| (parse_f64(p) - price).abs() < EPSILON)
+            .map_or(0.0, |(_, q)| parse_f64(q))
+    }
+
+    /// Opposite levels an order can trade with, best first, as (price
+    /// string, price, quantity).
+    fn crossing_levels(&self, order: &SimOrder) -> Vec<(String, f64, f64)> {
+        self.book
+            .levels(opposite(order.side))
+            .map(|(p, q)| (p.to_string(), parse_f64(p), parse_f64(q)))
+            .take_while(|(_, price, _)| within(order.side, *price, order.limit))
+            .collect()
+    }
+
+    /// Take liquidity from the levels an order crosses, removing it
+    /// from the book. Resting orders fill at their own price as the
+    /// maker; incoming orders at the level's price as the taker.
+    fn take(
+        &mut self,
+        order: &mut SimOrder,
+        liquidity: FillLiquidity,
+        events: &mut Vec<MatchEvent>,
+    ) {
+        for (level, level_price, quantity) in self.crossing_levels(order) {
+            let amount = quantity.min(order.remaining());
+            if amount <= 0.0 {
+                break;
+            }
+            let left = quantity - amount;
+            self.book.apply_change(&Level2Change {
+                order: opposite(order.side),
+                price: level.clone(),
+                quantity: if left < EPSILON {
+                    "0".to_string()
+                } else {
+                    decimal(left)
+                },
+            });
+            let (price, price_f) = match liquidity {
+                FillLiquidity::Maker => (order.price.clone(), order.limit),
+                _ => (level, level_price),
+            };
+            order.fill(price_f, amount);
+            events.push(MatchEvent::Fill {
+                order: order.clone(),
+                price,
+                amount,
+                liquidity,
+            });
+        }
+    }
+
+    /// Match a new order, or a stop-limit order that just triggered,
+    /// and book what is left. Returns the order's state afterwards.
+    fn execute(&mut self, mut order: SimOrder, events: &mut Vec<MatchEvent>) -> SimOrder {
+        let levels = self.crossing_levels(&order);
+
+        if order.has_option(OrderOption::MakerOrCancel) && !levels.is_empty() {
+            order.cancel(RejectReason::MakerOrCancelWouldTake);
+            events.push(MatchEvent::Cancelled(order.clone()));
+            return order;
+        }
+        if order.has_option(OrderOption::FillOrKill) {
+            let depth: f64 = levels.iter().map(|(_, _, q)| q).sum();
+            if depth + EPSILON < order.remaining() {
+                order.cancel(RejectReason::FillOrKillWouldNotFill);
+                events.push(MatchEvent::Cancelled(order.clone()));
+                return order;
+            }
+        }
+
+        self.take(&mut order, FillLiquidity::Taker, events);
+
+        if order.remaining() == 0.0 {
+            events.push(MatchEvent::Closed(order.clone()));
+        } else if order.has_option(OrderOption::FillOrKill) {
+            order.cancel(RejectReason::FillOrKillWouldNotFill);
+            events.push(MatchEvent::Cancelled(order.clone()));
+        } else if order.has_option(OrderOption::ImmediateOrCancel) {
+            order.cancel(RejectReason::ImmediateOrCancelWouldPost);
+            events.push(MatchEvent::Cancelled(order.clone()));
+        } else {
+            let ahead = if self.queue {
+                Some(self.level_quantity(order.side, order.limit))
+            } else {
+                None
+            };
+            events.push(MatchEvent::Booked(order.clone()));
+            let resting = Resting {
+                order: order.clone(),
+                ahead,
+            };
+            self.orders.insert(order.order_id, resting);
+        }
+        order
+    }
+
+    /// Submit a new order. Stop-limit orders rest untriggered;
+    /// anything else is matched immediately. Returns the order's state
+    /// afterwards.
+    pub(crate) fn submit(&mut self, order: SimOrder, events: &mut Vec<MatchEvent>) -> SimOrder {
+        if order.stop.is_some() {
+            let resting = Resting {
+                order: order.clone(),
+                ahead: None,
+            };
+            self.orders.insert(order.order_id, resting);
+            return order;
+        }
+        self.execute(order, events)
+    }
+
+    /// Cancel a resting order.
+    pub(crate) fn cancel(&mut self, order_id: OrderId, reason: RejectReason) -> Option<SimOrder> {
+        let mut resting = self.orders.remove(&order_id)?;
+        resting.order.cancel(reason);
+        Some(resting.order)
+    }
+
+    /// Allot the volume of a trade to resting orders on the maker side,
+    /// best price and then oldest order first.
+    fn match_trade(
+        &mut self,
+        taker: OrderSide,
+        price: f64,
+        volume: f64,
+        events: &mut Vec<MatchEvent>,
+    ) {
+        let side = opposite(taker);
+        let mut ids: Vec<(OrderId, f64)> = self
+            .orders
+            .values()
+            .filter(|r| r.order.side == side && r.order.stop.is_none())
+            .map(|r| (r.order.order_id, r.order.limit))
+            .collect();
+        ids.sort_by(|a, b| match side {
+            OrderSide::Buy => b.1.total_cmp(&a.1),
+            OrderSide::Sell => a.1.total_cmp(&b.1),
+        });
+
+        let mut volume = volume;
+        for (id, limit) in ids {
+            if volume <= EPSILON {
+                break;
+            }
+            let mut resting = match self.orders.remove(&id) {
+                Some(resting) => resting,
+                None => continue,
+            };
+            let at_price = (price - limit).abs() < EPSILON;
+            let through = !at_price && within(side, price, limit);
+            let fills = match (&mut resting.ahead, at_price) {
+                (Some(ahead), true) => {
+                    let consumed = ahead.min(volume);
+                    *ahead -= consumed;
+                    volume -= consumed;
+                    true
+                }
+                _ => through,
+            };
+            let amount = volume.min(resting.order.remaining());
+            if fills && amount > 0.0 {
+                volume -= amount;
+                resting.order.fill(limit, amount);
+                events.push(MatchEvent::Fill {
+                    order: resting.order.clone(),
+                    price: resting.order.price.clone(),
+                    amount,
+                    liquidity: FillLiquidity::Maker,
+                });
+            }
+            if resting.order.is_live {
+                self.orders.insert(id, resting);
+            } else {
+                events.push(MatchEvent::Closed(resting.order));
+            }
+        }
+    }
+
+    /// Apply an `l2_updates` message: allot its trades to resting
+    /// orders, update the book, then trigger stop-limit orders and
+    /// fill orders the book moved through.
+    pub(crate) fn apply(&mut self, l2: &Level2, events: &mut Vec<MatchEvent>) {
+        let trades: Vec<(OrderSide, f64, f64)> = l2
+            .trades
+            .iter()
+            .flatten()
+            .map(|t| (t.side, parse_f64(&t.price), parse_f64(&t.quantity)))
+            .collect();
+        for &(taker, price, volume) in &trades {
+            self.match_trade(taker, price, volume, events);
+        }
+        self.book.apply(l2);
+
+        let ids: Vec<OrderId> = self.orders.keys().copied().collect();
+        for id in ids {
+            let mut resting = match self.orders.remove(&id) {
+                Some(resting) => resting,
+                None => continue,
+            };
+
+            if let Some(stop) = resting.order.stop {
+                let side = resting.order.side;
+                let near = self.book.levels(side).next().map(|(p, _)| parse_f64(p));
+                let triggered = trades
+                    .iter()
+                    .map(|(_, price, _)| *price)
+                    .chain(near)
+                    .any(|price| within(opposite(side), price, stop));
+                if triggered {
+                    resting.order.stop = None;
+                    self.execute(resting.order, events);
+                } else {
+                    self.orders.insert(id, resting);
+                }
+                continue;
+            }
+
+            if let Some(ahead) = resting.ahead.as_mut() {
+                let level = self.level_quantity(resting.order.side, resting.order.limit);
+                *ahead = ahead.min(level);
+            }
+            self.take(&mut resting.order, FillLiquidity::Maker, events);
+            if resting.order.is_live {
+                self.orders.insert(id, resting);
+            } else {
+                events.push(MatchEvent::Closed(resting.order));
+            }
+        }
+    }
+}
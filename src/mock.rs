@@ -0,0 +1,600 @@
+//! In-process mock of the Gemini REST API, for tests without network.
+//!
+//! `MockServer` listens on a local port and answers the requests made
+//! by `Public` and `Private` pointed at `MockServer::uri`. Private
+//! requests are authenticated like Gemini does: the API key must
+//! match, `X-GEMINI-SIGNATURE` must be the HMAC-SHA384 of the payload
+//! under the API secret, the payload's `request` must match the path,
+//! and every nonce must be larger than the previous one.
+//!
+//! Every endpoint called by `Public` and `Private` has a built-in
+//! answer. Order endpoints keep a simple in-memory order list,
+//! endpoints that list account data answer with an empty list, and
+//! the others answer with fixed placeholder values. Fixtures set with
+//! `MockServer::fixture` or `MockServer::error` override the built-in
+//! answers, and serve any other path.
+//!
+//! Requires the `mock` feature.
+use crate::private::Private;
+use crate::types::{GError, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{body::to_bytes, Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Canned answer for an endpoint.
+#[derive(Debug, Clone)]
+pub struct Fixture {
+    pub status: u16,
+    pub body: String,
+}
+
+/// Request received by the server.
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+
+    /// Decoded `X-GEMINI-PAYLOAD` of private requests.
+    pub payload: Option<Value>,
+}
+
+struct State {
+    api_key: String,
+    api_secret: String,
+    last_nonce: Option<u64>,
+    fixtures: HashMap<String, Fixture>,
+    requests: Vec<MockRequest>,
+    orders: BTreeMap<u64, Value>,
+    next_order_id: u64,
+}
+
+fn error(status: StatusCode, reason: &str, message: &str) -> Fixture {
+    Fixture {
+        status: status.as_u16(),
+        body: json!({ "result": "error", "reason": reason, "message": message }).to_string(),
+    }
+}
+
+fn ok(body: Value) -> Fixture {
+    ok_text(body.to_string())
+}
+
+fn ok_text(body: String) -> Fixture {
+    Fixture {
+        status: StatusCode::OK.as_u16(),
+        body,
+    }
+}
+
+impl State {
+    /// Check the headers of a private request and return its payload.
+    fn authenticate(&mut self, req: &Request<Body>) -> std::result::Result<Value, Fixture> {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let bad_request =
+            |reason: &str, message: &str| Err(error(StatusCode::BAD_REQUEST, reason, message));
+
+        if header("X-GEMINI-APIKEY").as_deref() != Some(self.api_key.as_str()) {
+            return bad_request("InvalidApiKey", "Invalid API key");
+        }
+        let encoded = match header("X-GEMINI-PAYLOAD") {
+            Some(encoded) => encoded,
+            None => return bad_request("MissingPayloadHeader", "No payload was provided"),
+        };
+        let signature = Private::sign(&self.api_secret, &encoded);
+        if header("X-GEMINI-SIGNATURE").as_deref() != Some(signature.as_str()) {
+            return bad_request("InvalidSignature", "Invalid signature");
+        }
+
+        let payload: Value = match base64::decode(&encoded)
+            .ok()
+            .and_then(|p| serde_json::from_slice(&p).ok())
+        {
+            Some(payload) => payload,
+            None => return bad_request("InvalidJson", "Payload is not valid JSON"),
+        };
+        if payload["request"].as_str() != Some(req.uri().path()) {
+            return bad_request(
+                "InvalidRequestURL",
+                "Payload request does not match the endpoint",
+            );
+        }
+        let nonce = match payload["nonce"].as_u64() {
+            Some(nonce) => nonce,
+            None => return bad_request("InvalidNonce", "Missing nonce"),
+        };
+        if matches!(self.last_nonce, Some(last) if nonce <= last) {
+            return bad_request(
+                "InvalidNonce",
+                &format!("Nonce {} has not increased since your last call", nonce),
+            );
+        }
+        self.last_nonce = Some(nonce);
+        Ok(payload)
+    }
+
+    fn new_order(&mut self, payload: &Value) -> Fixture {
+        self.next_order_id += 1;
+        let id = self.next_order_id.to_string();
+        let now = chrono::Utc::now().timestamp_millis();
+        let order = json!({
+            "order_id": id,
+            "id": id,
+            "client_order_id": payload.get("client_order_id"),
+            "symbol": payload["symbol"],
+            "exchange": "gemini",
+            "avg_execution_price": "0.00",
+            "side": payload["side"],
+            "type": payload["type"],
+            "timestamp": (now / 1000).to_string(),
+            "timestampms": now,
+            "is_live": true,
+            "is_cancelled": false,
+            "is_hidden": false,
+            "was_forced": false,
+            "executed_amount": "0",
+            "remaining_amount": payload["amount"],
+            "options": payload.get("options").cloned().unwrap_or_else(|| json!([])),
+            "price": payload["price"],
+            "original_amount": payload["amount"],
+        });
+        self.orders.insert(self.next_order_id, order.clone());
+        ok(order)
+    }
+
+    fn cancel(&mut self, order_id: u64) -> Option<Value> {
+        let mut order = self.orders.remove(&order_id)?;
+        order["is_live"] = json!(false);
+        order["is_cancelled"] = json!(true);
+        order["reason"] = json!("Requested");
+        Some(order)
+    }
+
+    fn cancel_order(&mut self, payload: &Value) -> Fixture {
+        let order_id = payload["order_id"]
+            .as_u64()
+            .or_else(|| payload["order_id"].as_str()?.parse().ok());
+        match order_id.and_then(|id| self.cancel(id)) {
+            Some(order) => ok(order),
+            None => error(StatusCode::BAD_REQUEST, "OrderNotFound", "Order not found"),
+        }
+    }
+
+    fn cancel_all_orders(&mut self) -> Fixture {
+        let ids: Vec<u64> = self.orders.keys().copied().collect();
+        for id in &ids {
+            self.cancel(*id);
+        }
+        ok(json!({
+            "result": "ok",
+            "details": { "cancelRejects": [], "cancelledOrders": ids },
+        }))
+    }
+
+    /// Built-in answer for an authenticated private request.
+    fn private(&mut self, path: &str, payload: &Value) -> Option<Fixture> {
+        Some(match path {
+            "/v1/order/new" => self.new_order(payload),
+            "/v1/order/cancel" => self.cancel_order(payload),
+            "/v1/order/cancel/all" => self.cancel_all_orders(),
+            "/v1/balances"
+            | "/v1/mytrades"
+            | "/v1/positions"
+            | "/v1/perpetuals/fundingPayment"
+            | "/v1/custodyaccountfees" => ok(json!([])),
+            "/v1/notionalvolume" => ok(json!({
+                "date": chrono::Utc::now().format("%Y-%m-%d").to_string(),
+                "last_updated_ms": chrono::Utc::now().timestamp_millis(),
+                "web_maker_fee_bps": 25,
+                "web_taker_fee_bps": 35,
+                "web_auction_fee_bps": 25,
+                "api_maker_fee_bps": 10,
+                "api_taker_fee_bps": 35,
+                "api_auction_fee_bps": 20,
+                "fix_maker_fee_bps": 10,
+                "fix_taker_fee_bps": 35,
+                "fix_auction_fee_bps": 20,
+                "block_maker_fee_bps": 0,
+                "block_taker_fee_bps": 50,
+                "notional_30d_volume": 0,
+            })),
+            "/v1/margin" => ok(json!({
+                "margin_assets_value": "0",
+                "initial_margin": "0",
+                "available_margin": "0",
+                "margin_maintenance_limit": "0",
+                "leverage": "0",
+                "notional_value": "0",
+                "estimated_liquidation_price": null,
+                "initial_margin_positions": "0",
+                "reserved_margin": "0",
+                "reserved_margin_buys": "0",
+                "reserved_margin_sells": "0",
+                "buying_power": "0",
+                "selling_power": "0",
+            })),
+            "/v1/accounts/risk-stats" => ok(json!({
+                "product_type": "PerpetualSwapContract",
+                "mark_price": "100.00",
+                "index_price": "100.00",
+                "open_interest": "0",
+                "open_interest_notional": "0",
+            })),
+            _ => return None,
+        })
+    }
+
+    /// Built-in answer for a public request.
+    fn public(&self, path: &str) -> Option<Fixture> {
+        let (endpoint, arg) = match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => (path, ""),
+        };
+        Some(match (endpoint, arg) {
+            ("/v1", "symbols") => ok(json!(["btcusd", "ethusd", "btcgusdperp"])),
+            ("/v1", "feepromos") => ok(json!({ "symbols": [] })),
+            ("/v1/symbols/details", symbol) => ok(json!({
+                "symbol": symbol.to_uppercase(),
+                "base_currency": "BTC",
+                "quote_currency": "USD",
+                "tick_size": 1e-8,
+                "quote_increment": 0.01,
+                "min_order_size": "0.00001",
+                "status": "open",
+            })),
+            ("/v1/pubticker", _) => ok(json!({
+                "bid": "100.00",
+                "ask": "101.00",
+                "last": "100.50",
+                "volume": { "timestamp": chrono::Utc::now().timestamp_millis() },
+            })),
+            ("/v1/fundingamount", symbol) => {
+                let now = chrono::Utc::now();
+                let hour = now.timestamp() / 3600 * 3600;
+                ok(json!({
+                    "symbol": symbol,
+                    "fundingDateTime": now.format("%Y-%m-%dT%H:00:00.000Z").to_string(),
+                    "fundingTimestampMilliSecs": hour * 1000,
+                    "nextFundingTimestamp": (hour + 3600) * 1000,
+                    "amount": "0",
+                    "estimatedFundingAmount": "0",
+                }))
+            }
+            ("/v1/fundingamountreport", "records.xlsx") => ok_text(String::new()),
+            ("/v1/network", token) => ok(json!({
+                "token": token.to_uppercase(),
+                "network": ["ethereum"],
+            })),
+            _ => return None,
+        })
+    }
+
+    fn handle(&mut self, req: &Request<Body>) -> Fixture {
+        let path = req.uri().path().to_string();
+        let private = req.method() == Method::POST;
+
+        let payload = if private {
+            match self.authenticate(req) {
+                Ok(payload) => Some(payload),
+                Err(fixture) => return fixture,
+            }
+        } else {
+            None
+        };
+        self.requests.push(MockRequest {
+            method: req.method().clone(),
+            path: path.clone(),
+            query: req.uri().query().map(str::to_string),
+            payload: payload.clone(),
+        });
+
+        if let Some(fixture) = self.fixtures.get(&path) {
+            return fixture.clone();
+        }
+        let builtin = match payload {
+            Some(payload) => self.private(&path, &payload),
+            None => self.public(&path),
+        };
+        builtin.unwrap_or_else(|| {
+            error(
+                StatusCode::NOT_FOUND,
+                "EndpointNotFound",
+                &format!("No fixture for {}", path),
+            )
+        })
+    }
+}
+
+async fn serve(
+    state: Arc<Mutex<State>>,
+    req: Request<Body>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    // Private requests carry everything in headers; drain the body
+    // anyway so the connection can be reused.
+    let _ = to_bytes(body).await;
+    let req = Request::from_parts(parts, Body::empty());
+
+    let fixture = state.lock().unwrap().handle(&req);
+    let resp = Response::builder()
+        .status(fixture.status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(fixture.body))
+        .unwrap();
+    Ok(resp)
+}
+
+/// Mock Gemini REST server. Shuts down when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    /// Start a server on a free local port, accepting private
+    /// requests signed with `api_key` and `api_secret`.
+    pub async fn start(api_key: &str, api_secret: &str) -> Result<MockServer> {
+        let state = Arc::new(Mutex::new(State {
+            api_key: api_key.to_string(),
+            api_secret: api_secret.to_string(),
+            last_nonce: None,
+            fixtures: HashMap::new(),
+            requests: Vec::new(),
+            orders: BTreeMap::new(),
+            next_order_id: 0,
+        }));
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let state = service_state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| serve(state.clone(), req))) }
+        });
+
+        let server = Server::try_bind(&([127, 0, 0, 1], 0).into())
+            .map_err(GError::Http)?
+            .serve(make_service);
+        let addr = server.local_addr();
+
+        let (shutdown, rx) = oneshot::channel();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = rx.await;
+        }));
+
+        Ok(MockServer {
+            addr,
+            state,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    /// Base URI to pass to `Public::new` or `Private::new`.
+    pub fn uri(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Answer requests to `path` with `body` and HTTP 200.
+    pub fn fixture(&self, path: &str, body: &str) {
+        self.fixture_with_status(path, 200, body);
+    }
+
+    /// Answer requests to `path` with `body` and `status`.
+    pub fn fixture_with_status(&self, path: &str, status: u16, body: &str) {
+        let fixture = Fixture {
+            status,
+            body: body.to_string(),
+        };
+        let mut state = self.state.lock().unwrap();
+        state.fixtures.insert(path.to_string(), fixture);
+    }
+
+    /// Answer requests to `path` with a Gemini error response.
+    pub fn error(&self, path: &str, status: u16, reason: &str, message: &str) {
+        let body = json!({ "result": "error", "reason": reason, "message": message });
+        self.fixture_with_status(path, status, &body.to_string());
+    }
+
+    /// Remove the fixture of `path`, restoring the built-in answer.
+    pub fn clear_fixture(&self, path: &str) {
+        self.state.lock().unwrap().fixtures.remove(path);
+    }
+
+    /// Requests answered so far, oldest first. Requests that failed
+    /// authentication are not included.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Payloads of the orders currently open.
+    pub fn open_orders(&self) -> Vec<Value> {
+        let state = self.state.lock().unwrap();
+        state.orders.values().cloned().collect()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::public::Public;
+    use crate::structs::{OrderBuilder, OrderSide};
+    use hyper::Client;
+
+    const KEY: &str = "account-key";
+    const SECRET: &str = "secret";
+
+    fn reason<T: std::fmt::Debug>(result: Result<T>) -> String {
+        match result {
+            Err(GError::Gemini(e)) => e.reason,
+            other => panic!("expected a Gemini error, got {:?}", other),
+        }
+    }
+
+    /// Send a private request with a hand-made payload.
+    async fn raw(server: &MockServer, path: &str, payload: Value) -> (u16, Value) {
+        let payload = base64::encode(payload.to_string());
+        let req = Request::post(server.uri() + path)
+            .header("X-GEMINI-APIKEY", KEY)
+            .header("X-GEMINI-SIGNATURE", Private::sign(SECRET, &payload))
+            .header("X-GEMINI-PAYLOAD", payload)
+            .body(Body::empty())
+            .unwrap();
+        let resp = Client::new().request(req).await.unwrap();
+        let status = resp.status().as_u16();
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn order_round_trip() {
+        let server = MockServer::start(KEY, SECRET).await.unwrap();
+        let private = Private::new(&server.uri(), KEY, SECRET);
+
+        let order = OrderBuilder::limit("btcusd", OrderSide::Buy, 0.5, 100.0)
+            .client_order_id("c1")
+            .build()
+            .unwrap();
+        let resp = private.new_order(&order).await.unwrap();
+        assert!(resp.is_live);
+        assert_eq!(resp.client_order_id.as_deref(), Some("c1"));
+        assert_eq!(resp.remaining_amount, order.amount());
+        assert_eq!(server.open_orders().len(), 1);
+
+        let cancelled = private.cancel_order(resp.order_id).await.unwrap();
+        assert!(cancelled.is_cancelled);
+        assert!(server.open_orders().is_empty());
+        let again = private.cancel_order(resp.order_id).await;
+        assert_eq!(reason(again), "OrderNotFound");
+
+        private.new_order(&order).await.unwrap();
+        private.new_order(&order).await.unwrap();
+        let all = private.cancel_all_orders().await.unwrap();
+        assert_eq!(all.details.cancelled_orders.len(), 2);
+        assert!(server.open_orders().is_empty());
+
+        let paths: Vec<_> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths[0], "/v1/order/new");
+        assert_eq!(paths.last().unwrap(), "/v1/order/cancel/all");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_credentials() {
+        let server = MockServer::start(KEY, SECRET).await.unwrap();
+
+        let wrong_secret = Private::new(&server.uri(), KEY, "wrong");
+        assert_eq!(reason(wrong_secret.balances().await), "InvalidSignature");
+        let wrong_key = Private::new(&server.uri(), "other-key", SECRET);
+        assert_eq!(reason(wrong_key.balances().await), "InvalidApiKey");
+        assert!(server.requests().is_empty());
+
+        let (status, body) = raw(
+            &server,
+            "/v1/balances",
+            json!({ "request": "/v1/mytrades", "nonce": 1 }),
+        )
+        .await;
+        assert_eq!(status, 400);
+        assert_eq!(body["reason"], "InvalidRequestURL");
+    }
+
+    #[tokio::test]
+    async fn rejects_repeated_nonce() {
+        let server = MockServer::start(KEY, SECRET).await.unwrap();
+        let private = Private::new(&server.uri(), KEY, SECRET);
+        private.balances().await.unwrap();
+
+        let payload = json!({ "request": "/v1/balances", "nonce": 1 });
+        let (status, body) = raw(&server, "/v1/balances", payload).await;
+        assert_eq!(status, 400);
+        assert_eq!(body["reason"], "InvalidNonce");
+
+        let nonce = server.requests()[0].payload.as_ref().unwrap()["nonce"]
+            .as_u64()
+            .unwrap();
+        let payload = json!({ "request": "/v1/balances", "nonce": nonce });
+        let (_, body) = raw(&server, "/v1/balances", payload).await;
+        assert_eq!(body["reason"], "InvalidNonce");
+
+        let payload = json!({ "request": "/v1/balances", "nonce": nonce + 1 });
+        let (status, _) = raw(&server, "/v1/balances", payload).await;
+        assert_eq!(status, 200);
+        private.balances().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn builtin_answers() {
+        let server = MockServer::start(KEY, SECRET).await.unwrap();
+        let public = Public::new(&server.uri());
+        let private = Private::new(&server.uri(), KEY, SECRET);
+
+        assert!(public.get_symbols().await.unwrap().len() > 1);
+        let details = public.get_symbol_details("btcusd").await.unwrap();
+        assert_eq!(details.precision(), (8, 2));
+        assert!(public.get_ticker("btcusd").await.unwrap().mid() > 0.0);
+        let funding = public.get_funding_amount("btcgusdperp").await.unwrap();
+        assert_eq!(funding.symbol, "btcgusdperp");
+        assert_eq!(public.network("eth").await.unwrap().token, "ETH");
+        let report = public.get_funding_amount_report("btcgusdperp", None, None, Some(10));
+        assert!(report.await.is_ok());
+
+        let fees = private.fee_schedule().await.unwrap();
+        assert_eq!(fees["btcusd"].maker_fee_bps, 10);
+        private.margin("btcgusdperp").await.unwrap();
+        private.risk_stats("btcgusdperp").await.unwrap();
+        private.positions().await.unwrap();
+        private.funding_payments(None, None).await.unwrap();
+        private.custody_account_fees(None, None).await.unwrap();
+        private.recent_trades("btcusd").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fixtures_override_builtin_answers() {
+        let server = MockServer::start(KEY, SECRET).await.unwrap();
+        let public = Public::new(&server.uri());
+        let private = Private::new(&server.uri(), KEY, SECRET);
+
+        server.fixture(
+            "/v1/pubticker/btcusd",
+            r#"{"bid":"10.00","ask":"12.00","last":"11.00"}"#,
+        );
+        assert_eq!(public.get_ticker("btcusd").await.unwrap().mid(), 11.0);
+
+        server.error("/v1/order/new", 400, "InsufficientFunds", "Not enough");
+        let order = OrderBuilder::limit("btcusd", OrderSide::Buy, 1.0, 100.0)
+            .build()
+            .unwrap();
+        assert_eq!(reason(private.new_order(&order).await), "InsufficientFunds");
+        assert!(server.open_orders().is_empty());
+
+        server.clear_fixture("/v1/order/new");
+        private.new_order(&order).await.unwrap();
+        assert_eq!(server.open_orders().len(), 1);
+
+        let missing = public.get_funding_amount_report("btcusd", None, None, None);
+        server.error("/v1/fundingamountreport/records.xlsx", 404, "NotFound", "");
+        assert_eq!(reason(missing.await), "NotFound");
+
+        server.fixture("/v1/feepromos", r#"{"symbols":["BTCUSD"]}"#);
+        let fees = private.fee_schedule().await.unwrap();
+        assert!(fees["btcusd"].promo);
+        assert_eq!(fees["btcusd"].maker_fee_bps, 10);
+        assert!(!fees["ethusd"].promo);
+    }
+}
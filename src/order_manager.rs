@@ -0,0 +1,732 @@
+//! Order lifecycle tracking from REST acknowledgements and order events.
+//!
+//! `OrderManager` correlates the `OrderResponse` returned by
+//! `Private::new_order`/`cancel_order` with the `OrderStatus` events
+//! of the order events feed, matching on `OrderId` or, before the
+//! order id is known, on `client_order_id`. Events are checked against
+//! the order lifecycle, and every state change is published to
+//! subscribers.
+use crate::private::Private;
+use crate::structs::order::{Order, OrderId, OrderResponse, OrderSide, RejectReason};
+use crate::structs::wsfeed::{Fill, OrderEventType, OrderMessage, OrderStatus};
+use crate::types::{GError, Result};
+use crate::util::{parse_f64, try_parse_f64};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+/// Whether the executed amount covers the whole order. Malformed or
+/// missing amounts never count as filled.
+fn is_fully_filled(executed: &str, original: &str) -> bool {
+    match (try_parse_f64(executed), try_parse_f64(original)) {
+        (Some(executed), Some(original)) => original > 0.0 && executed >= original,
+        _ => false,
+    }
+}
+
+/// Lifecycle state of an order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OrderState {
+    /// Sent, but not yet acknowledged.
+    Pending,
+    Accepted,
+    Booked,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Rejected,
+
+    /// Closed without being filled or cancelled, e.g. when the close
+    /// event was the first one seen for the order.
+    Closed,
+}
+
+impl OrderState {
+    /// True if the order can no longer trade.
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            OrderState::Filled | OrderState::Cancelled | OrderState::Rejected | OrderState::Closed
+        )
+    }
+
+    /// Position of a live state in the lifecycle.
+    fn rank(self) -> u8 {
+        match self {
+            OrderState::Pending => 0,
+            OrderState::Accepted => 1,
+            OrderState::Booked => 2,
+            OrderState::PartiallyFilled => 3,
+            _ => 4,
+        }
+    }
+
+    /// State after applying an event, or `None` if the event is not a
+    /// valid transition from this state.
+    ///
+    /// `acked` is true if the current state was set by a REST response
+    /// rather than an event. The response usually arrives before the
+    /// events describing the same change, so an event that the
+    /// response already accounted for keeps the state as it is.
+    fn next(self, event: OrderEventType, fully_filled: bool, acked: bool) -> Option<OrderState> {
+        use OrderEventType as E;
+        use OrderState as S;
+
+        if acked && self.is_terminal() {
+            return Some(self);
+        }
+        let filled = if fully_filled {
+            S::Filled
+        } else {
+            S::PartiallyFilled
+        };
+        match (self, event) {
+            (S::Pending, E::Accepted) => Some(S::Accepted),
+            (s, E::Accepted) if acked && !s.is_terminal() => Some(s),
+            (S::Pending, E::Rejected) | (S::Accepted, E::Rejected) => Some(S::Rejected),
+            (s, E::Initial) | (s, E::Booked) if !s.is_terminal() => {
+                Some(if s == S::PartiallyFilled {
+                    s
+                } else {
+                    S::Booked
+                })
+            }
+            (s, E::Fill) if !s.is_terminal() => Some(filled),
+            (s, E::Cancelled) if !s.is_terminal() => Some(S::Cancelled),
+            (s, E::CancelRejected) if !s.is_terminal() => Some(s),
+            (s, E::Closed) if s.is_terminal() => Some(s),
+            (_, E::Closed) if fully_filled => Some(S::Filled),
+            (_, E::Closed) => Some(S::Closed),
+            _ => None,
+        }
+    }
+}
+
+/// Current view of a single order.
+#[derive(Debug, Clone)]
+pub struct TrackedOrder {
+    pub order_id: Option<OrderId>,
+    pub client_order_id: Option<String>,
+    pub symbol: String,
+    pub side: OrderSide,
+    pub price: String,
+    pub original_amount: String,
+    pub state: OrderState,
+
+    /// Most recent rejection or cancellation reason.
+    pub reason: Option<RejectReason>,
+
+    /// Fills in the order they were received.
+    pub fills: Vec<Fill>,
+
+    /// Cumulative filled amount, summed over `fills`.
+    pub filled_amount: f64,
+
+    /// Cumulative notional (price * amount), summed over `fills`.
+    pub filled_notional: f64,
+
+    /// Cumulative fees, per fee currency.
+    pub fees: HashMap<String, f64>,
+}
+
+impl TrackedOrder {
+    fn from_order(order: &Order) -> TrackedOrder {
+        TrackedOrder {
+            order_id: None,
+            client_order_id: Some(order.client_order_id().to_string()),
+            symbol: order.symbol().to_string(),
+            side: order.side(),
+            price: order.price().to_string(),
+            original_amount: order.amount().to_string(),
+            state: OrderState::Pending,
+            reason: None,
+            fills: Vec::new(),
+            filled_amount: 0.0,
+            filled_notional: 0.0,
+            fees: HashMap::new(),
+        }
+    }
+
+    fn from_response(resp: &OrderResponse) -> TrackedOrder {
+        TrackedOrder {
+            order_id: Some(resp.order_id),
+            client_order_id: resp.client_order_id.clone(),
+            symbol: resp.symbol.clone(),
+            side: resp.side,
+            price: resp.price.clone(),
+            original_amount: resp.original_amount.clone(),
+            state: OrderState::Pending,
+            reason: None,
+            fills: Vec::new(),
+            filled_amount: 0.0,
+            filled_notional: 0.0,
+            fees: HashMap::new(),
+        }
+    }
+
+    fn from_status(status: &OrderStatus) -> TrackedOrder {
+        TrackedOrder {
+            order_id: Some(status.order_id),
+            client_order_id: status.client_order_id.clone(),
+            symbol: status.symbol.clone(),
+            side: status.side,
+            price: status.price.clone(),
+            original_amount: status.original_amount.clone(),
+            state: OrderState::Pending,
+            reason: None,
+            fills: Vec::new(),
+            filled_amount: 0.0,
+            filled_notional: 0.0,
+            fees: HashMap::new(),
+        }
+    }
+
+    /// Volume-weighted average fill price.
+    pub fn average_price(&self) -> Option<f64> {
+        if self.filled_amount > 0.0 {
+            Some(self.filled_notional / self.filled_amount)
+        } else {
+            None
+        }
+    }
+
+    /// Amount not yet filled.
+    pub fn remaining_amount(&self) -> f64 {
+        let original = parse_f64(&self.original_amount);
+        (original - self.filled_amount).max(0.0)
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.state.is_terminal()
+    }
+
+    fn add_fill(&mut self, fill: &Fill) {
+        let amount = parse_f64(&fill.amount);
+        let price = parse_f64(&fill.price);
+        let fee = parse_f64(&fill.fee);
+        self.filled_amount += amount;
+        self.filled_notional += amount * price;
+        *self.fees.entry(fill.fee_currency.clone()).or_insert(0.0) += fee;
+        self.fills.push(fill.clone());
+    }
+}
+
+/// State change published by the `OrderManager`.
+#[derive(Debug, Clone)]
+pub struct OrderUpdate {
+    pub previous: OrderState,
+
+    /// Event that caused the change, or `None` for a REST response.
+    pub event: Option<OrderEventType>,
+
+    /// Fill contained in the event, if any.
+    pub fill: Option<Fill>,
+
+    pub order: TrackedOrder,
+}
+
+#[derive(Default)]
+struct Inner {
+    orders: HashMap<OrderId, TrackedOrder>,
+
+    /// Orders whose current state was set by a REST response.
+    acked: HashSet<OrderId>,
+
+    /// Orders sent but not yet matched to an order id.
+    pending: HashMap<String, TrackedOrder>,
+
+    subscribers: Vec<mpsc::UnboundedSender<OrderUpdate>>,
+}
+
+impl Inner {
+    /// Find the tracked order for an id, adopting a pending order with
+    /// the same client order id if necessary.
+    fn entry(
+        &mut self,
+        order_id: OrderId,
+        client_order_id: Option<&String>,
+    ) -> Option<&mut TrackedOrder> {
+        if !self.orders.contains_key(&order_id) {
+            let mut order = client_order_id.and_then(|id| self.pending.remove(id))?;
+            order.order_id = Some(order_id);
+            self.orders.insert(order_id, order);
+        }
+        self.orders.get_mut(&order_id)
+    }
+
+    fn publish(&mut self, update: OrderUpdate) {
+        self.subscribers.retain(|s| s.send(update.clone()).is_ok());
+    }
+}
+
+/// Tracks the lifecycle of orders placed through `Private`.
+///
+/// Orders in a terminal state are kept until `purge_closed`, so that
+/// late events and acknowledgements are still recognized.
+#[derive(Default)]
+pub struct OrderManager {
+    inner: Mutex<Inner>,
+}
+
+impl OrderManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking an order before it is sent. The order's client
+    /// order id is used to match events that arrive before the REST
+    /// response.
+    pub fn track(&self, order: &Order) {
+        if order.client_order_id().is_empty() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.pending.insert(
+            order.client_order_id().to_string(),
+            TrackedOrder::from_order(order),
+        );
+    }
+
+    /// Send an order through `private`, tracking it from submission.
+    pub async fn new_order(&self, private: &Private, order: &Order) -> Result<OrderResponse> {
+        self.track(order);
+        let result = private.new_order(order).await;
+        match &result {
+            Ok(resp) => self.apply_response(resp),
+            Err(_) => {
+                let mut inner = self.inner.lock().unwrap();
+                inner.pending.remove(order.client_order_id());
+            }
+        }
+        result
+    }
+
+    /// Cancel an order through `private`, applying the response.
+    pub async fn cancel_order(
+        &self,
+        private: &Private,
+        order_id: OrderId,
+    ) -> Result<OrderResponse> {
+        let resp = private.cancel_order(order_id).await?;
+        self.apply_response(&resp);
+        Ok(resp)
+    }
+
+    /// Apply a REST response from creating or cancelling an order.
+    ///
+    /// Responses may arrive after the corresponding events, so they
+    /// only ever move an order forward in its lifecycle. Events that
+    /// arrive after a response and describe the same change are
+    /// accepted, and their fills are still recorded.
+    pub fn apply_response(&self, resp: &OrderResponse) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner
+            .entry(resp.order_id, resp.client_order_id.as_ref())
+            .is_none()
+        {
+            inner
+                .orders
+                .insert(resp.order_id, TrackedOrder::from_response(resp));
+        }
+        let order = inner.orders.get_mut(&resp.order_id).unwrap();
+
+        let executed = parse_f64(&resp.executed_amount);
+        let state = if resp.is_cancelled {
+            OrderState::Cancelled
+        } else if is_fully_filled(&resp.executed_amount, &resp.original_amount) {
+            OrderState::Filled
+        } else if executed > 0.0 {
+            OrderState::PartiallyFilled
+        } else if resp.is_live {
+            OrderState::Accepted
+        } else if resp.reason.is_some() {
+            OrderState::Rejected
+        } else {
+            return;
+        };
+
+        let previous = order.state;
+        let forward = if previous.is_terminal() {
+            false
+        } else {
+            state.is_terminal() || state.rank() > previous.rank()
+        };
+        if !forward {
+            return;
+        }
+        order.state = state;
+        if resp.reason.is_some() {
+            order.reason = resp.reason.clone();
+        }
+        let update = OrderUpdate {
+            previous,
+            event: None,
+            fill: None,
+            order: order.clone(),
+        };
+        inner.acked.insert(resp.order_id);
+        inner.publish(update);
+    }
+
+    /// Apply a single order event.
+    ///
+    /// Fails with `GError::InvalidOrderTransition` if the event is not
+    /// valid for the current state of the order, in which case the
+    /// order is left unchanged.
+    pub fn apply_event(&self, status: &OrderStatus) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner
+            .entry(status.order_id, status.client_order_id.as_ref())
+            .is_none()
+        {
+            inner
+                .orders
+                .insert(status.order_id, TrackedOrder::from_status(status));
+        }
+        let acked = inner.acked.contains(&status.order_id);
+        let order = inner.orders.get_mut(&status.order_id).unwrap();
+
+        if let Some(fill) = &status.fill {
+            if order.fills.iter().any(|f| f.trade_id == fill.trade_id) {
+                return Ok(());
+            }
+        }
+
+        let fully_filled = !status.is_cancelled
+            && is_fully_filled(&status.executed_amount, &status.original_amount);
+        let previous = order.state;
+        let state = previous
+            .next(status.event_type, fully_filled, acked)
+            .ok_or(GError::InvalidOrderTransition {
+                order_id: status.order_id,
+                state: previous,
+                event: status.event_type,
+            })?;
+
+        if let Some(fill) = &status.fill {
+            order.add_fill(fill);
+        }
+        if state != previous {
+            inner.acked.remove(&status.order_id);
+        }
+        let order = inner.orders.get_mut(&status.order_id).unwrap();
+        order.state = state;
+        if status.reason.is_some() {
+            order.reason = status.reason.clone();
+        }
+        let update = OrderUpdate {
+            previous,
+            event: Some(status.event_type),
+            fill: status.fill.clone(),
+            order: order.clone(),
+        };
+        inner.publish(update);
+        Ok(())
+    }
+
+    /// Apply every order event in a message from the order events feed.
+    ///
+    /// All events are applied; the first invalid transition, if any,
+    /// is returned.
+    pub fn apply_message(&self, msg: &OrderMessage) -> Result<()> {
+        let mut result = Ok(());
+        if let OrderMessage::Orders(orders) = msg {
+            for status in orders {
+                let applied = self.apply_event(status);
+                if result.is_ok() {
+                    result = applied;
+                }
+            }
+        }
+        result
+    }
+
+    /// Current view of an order.
+    pub fn order(&self, order_id: OrderId) -> Option<TrackedOrder> {
+        self.inner.lock().unwrap().orders.get(&order_id).cloned()
+    }
+
+    /// Current view of an order by client order id, including orders
+    /// that have not been acknowledged yet.
+    pub fn order_by_client_id(&self, client_order_id: &str) -> Option<TrackedOrder> {
+        let inner = self.inner.lock().unwrap();
+        inner.pending.get(client_order_id).cloned().or_else(|| {
+            inner
+                .orders
+                .values()
+                .find(|o| o.client_order_id.as_deref() == Some(client_order_id))
+                .cloned()
+        })
+    }
+
+    /// All orders that are pending or live.
+    pub fn open_orders(&self) -> Vec<TrackedOrder> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .pending
+            .values()
+            .chain(inner.orders.values())
+            .filter(|o| o.is_open())
+            .cloned()
+            .collect()
+    }
+
+    /// Order ids of every open, acknowledged order.
+    pub fn open_order_ids(&self) -> HashSet<OrderId> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .orders
+            .iter()
+            .filter(|(_, o)| o.is_open())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Forget orders in a terminal state.
+    pub fn purge_closed(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.orders.retain(|_, o| o.is_open());
+        let Inner { orders, acked, .. } = &mut *inner;
+        acked.retain(|id| orders.contains_key(id));
+    }
+
+    /// Stream of every subsequent state change.
+    pub fn updates(&self) -> UnboundedReceiverStream<OrderUpdate> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.inner.lock().unwrap().subscribers.push(tx);
+        UnboundedReceiverStream::new(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response(executed: &str, remaining: &str, live: bool, cancelled: bool) -> OrderResponse {
+        serde_json::from_value(json!({
+            "order_id": "1",
+            "client_order_id": "c1",
+            "symbol": "btcusd",
+            "exchange": "gemini",
+            "price": "100.00",
+            "avg_execution_price": "0.00",
+            "side": "buy",
+            "type": "exchange limit",
+            "options": [],
+            "is_live": live,
+            "is_cancelled": cancelled,
+            "executed_amount": executed,
+            "remaining_amount": remaining,
+            "original_amount": "1",
+            "is_hidden": false,
+        }))
+        .unwrap()
+    }
+
+    fn event(
+        event: &str,
+        remaining: &str,
+        live: bool,
+        cancelled: bool,
+        fill: Option<(&str, &str, &str)>,
+    ) -> OrderStatus {
+        let fill = fill.map(|(trade_id, amount, price)| {
+            json!({
+                "trade_id": trade_id,
+                "liquidity": "Taker",
+                "price": price,
+                "amount": amount,
+                "fee": "0.01",
+                "fee_currency": "USD",
+            })
+        });
+        let executed = (1.0 - parse_f64(remaining)).to_string();
+        serde_json::from_value(json!({
+            "type": event,
+            "order_id": "1",
+            "client_order_id": "c1",
+            "symbol": "btcusd",
+            "side": "buy",
+            "order_type": "exchange limit",
+            "timestampms": 0,
+            "is_live": live,
+            "is_cancelled": cancelled,
+            "is_hidden": false,
+            "executed_amount": executed,
+            "remaining_amount": remaining,
+            "original_amount": "1",
+            "price": "100.00",
+            "fill": fill,
+        }))
+        .unwrap()
+    }
+
+    fn state(manager: &OrderManager) -> OrderState {
+        manager.order(OrderId(1)).unwrap().state
+    }
+
+    #[test]
+    fn accepted_ack_before_events() {
+        let manager = OrderManager::new();
+        manager.apply_response(&response("0", "1", true, false));
+        assert_eq!(state(&manager), OrderState::Accepted);
+        manager
+            .apply_event(&event("accepted", "1", true, false, None))
+            .unwrap();
+        manager
+            .apply_event(&event("booked", "1", true, false, None))
+            .unwrap();
+        assert_eq!(state(&manager), OrderState::Booked);
+    }
+
+    #[test]
+    fn accepted_events_before_ack() {
+        let manager = OrderManager::new();
+        manager
+            .apply_event(&event("accepted", "1", true, false, None))
+            .unwrap();
+        manager
+            .apply_event(&event("booked", "1", true, false, None))
+            .unwrap();
+        manager.apply_response(&response("0", "1", true, false));
+        assert_eq!(state(&manager), OrderState::Booked);
+    }
+
+    #[test]
+    fn cancel_ack_before_events() {
+        let manager = OrderManager::new();
+        manager.apply_response(&response("0", "1", true, false));
+        manager
+            .apply_event(&event("booked", "1", true, false, None))
+            .unwrap();
+        manager.apply_response(&response("0", "1", false, true));
+        assert_eq!(state(&manager), OrderState::Cancelled);
+        manager
+            .apply_event(&event("cancelled", "1", false, true, None))
+            .unwrap();
+        manager
+            .apply_event(&event("closed", "1", false, true, None))
+            .unwrap();
+        assert_eq!(state(&manager), OrderState::Cancelled);
+    }
+
+    #[test]
+    fn cancel_events_before_ack() {
+        let manager = OrderManager::new();
+        manager
+            .apply_event(&event("booked", "1", true, false, None))
+            .unwrap();
+        manager
+            .apply_event(&event("cancelled", "1", false, true, None))
+            .unwrap();
+        manager.apply_response(&response("0", "1", false, true));
+        assert_eq!(state(&manager), OrderState::Cancelled);
+    }
+
+    #[test]
+    fn filled_ack_before_events_records_fills() {
+        let manager = OrderManager::new();
+        manager.apply_response(&response("1", "0", false, false));
+        assert_eq!(state(&manager), OrderState::Filled);
+
+        let fills = [
+            event("accepted", "1", true, false, None),
+            event("fill", "0.6", true, false, Some(("t1", "0.4", "100"))),
+            event("fill", "0", false, false, Some(("t2", "0.6", "110"))),
+            event("closed", "0", false, false, None),
+        ];
+        for status in &fills {
+            manager.apply_event(status).unwrap();
+        }
+        let order = manager.order(OrderId(1)).unwrap();
+        assert_eq!(order.state, OrderState::Filled);
+        assert_eq!(order.fills.len(), 2);
+        assert!((order.filled_amount - 1.0).abs() < 1e-9);
+        assert!((order.average_price().unwrap() - 106.0).abs() < 1e-9);
+        assert!((order.fees["USD"] - 0.02).abs() < 1e-9);
+    }
+
+    #[test]
+    fn filled_events_before_ack() {
+        let manager = OrderManager::new();
+        let fills = [
+            event("accepted", "1", true, false, None),
+            event("fill", "0.6", true, false, Some(("t1", "0.4", "100"))),
+            event("fill", "0", false, false, Some(("t2", "0.6", "110"))),
+        ];
+        for status in &fills {
+            manager.apply_event(status).unwrap();
+        }
+        assert_eq!(state(&manager), OrderState::Filled);
+        manager.apply_response(&response("1", "0", false, false));
+        let order = manager.order(OrderId(1)).unwrap();
+        assert_eq!(order.state, OrderState::Filled);
+        assert!((order.filled_amount - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn duplicate_fill_is_ignored() {
+        let manager = OrderManager::new();
+        let fill = event("fill", "0.6", true, false, Some(("t1", "0.4", "100")));
+        manager.apply_event(&fill).unwrap();
+        manager.apply_event(&fill).unwrap();
+        let order = manager.order(OrderId(1)).unwrap();
+        assert_eq!(order.state, OrderState::PartiallyFilled);
+        assert_eq!(order.fills.len(), 1);
+    }
+
+    #[test]
+    fn fill_without_amounts_does_not_complete_the_order() {
+        let manager = OrderManager::new();
+        let mut fill = event("fill", "0.6", true, false, Some(("t1", "0.4", "100")));
+        fill.executed_amount = "".to_string();
+        fill.remaining_amount = "".to_string();
+        manager.apply_event(&fill).unwrap();
+        assert_eq!(state(&manager), OrderState::PartiallyFilled);
+
+        let fill = event("fill", "0", false, false, Some(("t2", "0.6", "100")));
+        manager.apply_event(&fill).unwrap();
+        assert_eq!(state(&manager), OrderState::Filled);
+    }
+
+    #[test]
+    fn event_after_event_terminal_is_rejected() {
+        let manager = OrderManager::new();
+        manager
+            .apply_event(&event("booked", "1", true, false, None))
+            .unwrap();
+        manager
+            .apply_event(&event("cancelled", "1", false, true, None))
+            .unwrap();
+        let fill = event("fill", "0", false, false, Some(("t1", "1", "100")));
+        match manager.apply_event(&fill) {
+            Err(GError::InvalidOrderTransition { state, event, .. }) => {
+                assert_eq!(state, OrderState::Cancelled);
+                assert_eq!(event, OrderEventType::Fill);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(manager.order(OrderId(1)).unwrap().fills.is_empty());
+    }
+
+    #[test]
+    fn tracked_order_is_adopted_by_client_order_id() {
+        let manager = OrderManager::new();
+        let order = crate::structs::OrderBuilder::limit("btcusd", OrderSide::Buy, 1.0, 100.0)
+            .client_order_id("c1")
+            .build()
+            .unwrap();
+        manager.track(&order);
+        assert_eq!(
+            manager.order_by_client_id("c1").unwrap().state,
+            OrderState::Pending
+        );
+        manager
+            .apply_event(&event("accepted", "1", true, false, None))
+            .unwrap();
+        assert_eq!(state(&manager), OrderState::Accepted);
+        assert_eq!(manager.open_orders().len(), 1);
+    }
+}
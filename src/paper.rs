@@ -0,0 +1,547 @@
+//! Paper trading against a local matching simulator.
+//!
+//! `PaperTrading` implements `Trading` without touching Gemini. Orders
+//! are matched against `OrderBook`s fed from a live or recorded
+//! `l2_updates` feed, and every state change is published as a
+//! synthetic `OrderStatus` event, as on the order events feed.
+//!
+//! Matching follows the rules of the `matching` module shared with
+//! backtests, without queue modeling: resting orders only fill on
+//! trades at a strictly better price than their own. Takers pay the
+//! taker fee and resting orders the maker fee. Fills on perpetual
+//! contracts only charge fees; positions are not simulated.
+use crate::book::OrderBook;
+use crate::matching::{
+    check_options, decimal, gemini_error, now_ms, reject, MatchEvent, Matcher, SimOrder, EPSILON,
+};
+use crate::structs::order::{Order, OrderId, OrderResponse, OrderSide, RejectReason};
+use crate::structs::private::{
+    AccountBalance, AccountTrade, CancelDetails, CancelResponse, FeeSchedule,
+};
+use crate::structs::wsfeed::{
+    Fill, FillLiquidity, Level2, MarketDataMessage, OrderEventType, OrderMessage, OrderStatus,
+};
+use crate::trading::Trading;
+use crate::types::Result;
+use crate::util::parse_f64;
+use futures::future::{self, BoxFuture, FutureExt};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+/// Fees charged by the simulator, in basis points of the notional.
+#[derive(Debug, Clone, Copy)]
+pub struct PaperFees {
+    pub maker_fee_bps: f64,
+    pub taker_fee_bps: f64,
+}
+
+impl From<&FeeSchedule> for PaperFees {
+    fn from(schedule: &FeeSchedule) -> Self {
+        PaperFees {
+            maker_fee_bps: schedule.maker_fee_bps as f64,
+            taker_fee_bps: schedule.taker_fee_bps as f64,
+        }
+    }
+}
+
+impl Default for PaperFees {
+    /// Gemini's base ActiveTrader tier.
+    fn default() -> Self {
+        PaperFees {
+            maker_fee_bps: 20.0,
+            taker_fee_bps: 40.0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    fees: PaperFees,
+    markets: HashMap<String, Matcher>,
+    balances: HashMap<String, f64>,
+    /// Account trades per symbol, newest first.
+    trades: HashMap<String, Vec<AccountTrade>>,
+    next_order_id: u64,
+    next_trade_id: u64,
+    subscribers: Vec<mpsc::UnboundedSender<OrderMessage>>,
+}
+
+impl Inner {
+    fn balance(&self, currency: &str) -> f64 {
+        self.balances.get(currency).copied().unwrap_or(0.0)
+    }
+
+    fn add(&mut self, currency: &str, amount: f64) {
+        *self.balances.entry(currency.to_string()).or_insert(0.0) += amount;
+    }
+
+    fn market(&mut self, symbol: &str) -> &mut Matcher {
+        self.markets
+            .entry(symbol.to_string())
+            .or_insert_with(|| Matcher::new(symbol, false))
+    }
+
+    /// Open orders of every symbol.
+    fn orders(&self) -> impl Iterator<Item = &SimOrder> {
+        self.markets.values().flat_map(|m| m.orders())
+    }
+
+    /// Amount of `currency` reserved by open orders.
+    fn reserved(&self, currency: &str) -> f64 {
+        self.orders()
+            .filter(|o| !Order::is_perpetual(&o.symbol))
+            .filter_map(|o| {
+                let (base, quote) = Order::currencies(&o.symbol)?;
+                match o.side {
+                    OrderSide::Buy if quote == currency => Some(o.remaining() * o.limit),
+                    OrderSide::Sell if base == currency => Some(o.remaining()),
+                    _ => None,
+                }
+            })
+            .sum()
+    }
+
+    fn available(&self, currency: &str) -> f64 {
+        self.balance(currency) - self.reserved(currency)
+    }
+
+    fn publish(&mut self, events: Vec<OrderStatus>) {
+        if events.is_empty() {
+            return;
+        }
+        self.subscribers
+            .retain(|s| s.send(OrderMessage::Orders(events.clone())).is_ok());
+    }
+
+    /// Record a fill of `order`, already applied to it by the matcher,
+    /// in balances and the trade history.
+    fn fill(
+        &mut self,
+        order: &SimOrder,
+        price: &str,
+        amount: f64,
+        liquidity: FillLiquidity,
+    ) -> OrderStatus {
+        let notional = amount * parse_f64(price);
+        let bps = match liquidity {
+            FillLiquidity::Maker => self.fees.maker_fee_bps,
+            _ => self.fees.taker_fee_bps,
+        };
+        let fee = notional * bps / 10_000.0;
+        let (base, quote) = Order::currencies(&order.symbol).unwrap_or_default();
+
+        if !Order::is_perpetual(&order.symbol) {
+            match order.side {
+                OrderSide::Buy => {
+                    self.add(&base, amount);
+                    self.add(&quote, -notional);
+                }
+                OrderSide::Sell => {
+                    self.add(&base, -amount);
+                    self.add(&quote, notional);
+                }
+            }
+        }
+        self.add(&quote, -fee);
+
+        self.next_trade_id += 1;
+        let trade_id = self.next_trade_id;
+        let timestampms = now_ms();
+        let trade = AccountTrade {
+            price: price.to_string(),
+            amount: decimal(amount),
+            timestamp: timestampms / 1000,
+            timestampms,
+            side: order.side,
+            aggressor: liquidity == FillLiquidity::Taker,
+            fee_currency: quote.clone(),
+            fee_amount: decimal(fee),
+            tid: trade_id,
+            order_id: order.order_id.to_string(),
+            client_order_id: order.client_order_id.clone(),
+            is_auction_fill: false,
+        };
+        self.trades
+            .entry(order.symbol.clone())
+            .or_default()
+            .insert(0, trade);
+
+        let fill = Fill {
+            trade_id: trade_id.to_string(),
+            liquidity,
+            price: price.to_string(),
+            amount: decimal(amount),
+            fee: decimal(fee),
+            fee_currency: quote,
+        };
+        order.status(OrderEventType::Fill, Some(fill))
+    }
+
+    /// Turn matcher events into order events, recording fills.
+    fn statuses(&mut self, matches: Vec<MatchEvent>) -> Vec<OrderStatus> {
+        matches
+            .into_iter()
+            .map(|event| match event {
+                MatchEvent::Fill {
+                    order,
+                    price,
+                    amount,
+                    liquidity,
+                } => self.fill(&order, &price, amount, liquidity),
+                MatchEvent::Booked(order) => order.status(OrderEventType::Booked, None),
+                MatchEvent::Cancelled(order) => order.status(OrderEventType::Cancelled, None),
+                MatchEvent::Closed(order) => order.status(OrderEventType::Closed, None),
+            })
+            .collect()
+    }
+
+    fn new_order(&mut self, order: &Order) -> Result<OrderResponse> {
+        let (base, quote) = match Order::currencies(order.symbol()) {
+            Some(currencies) => currencies,
+            None => return Err(reject(order, RejectReason::InvalidSymbol)),
+        };
+        check_options(order)?;
+
+        let amount = parse_f64(order.amount());
+        let price = parse_f64(order.price());
+        if !Order::is_perpetual(order.symbol()) {
+            let (currency, required) = match order.side() {
+                OrderSide::Buy => (
+                    quote,
+                    amount * price * (1.0 + self.fees.taker_fee_bps / 10_000.0),
+                ),
+                OrderSide::Sell => (base, amount),
+            };
+            if self.available(&currency) + EPSILON < required {
+                return Err(reject(order, RejectReason::InsufficientFunds));
+            }
+        }
+
+        self.next_order_id += 1;
+        let sim = SimOrder::new(OrderId(self.next_order_id), order);
+        let mut events = vec![sim.status(OrderEventType::Accepted, None)];
+        let mut matches = Vec::new();
+        let sim = self.market(&sim.symbol).submit(sim, &mut matches);
+        events.extend(self.statuses(matches));
+        self.publish(events);
+        Ok(sim.response())
+    }
+
+    fn cancel_order(&mut self, order_id: OrderId) -> Result<OrderResponse> {
+        let order = self
+            .markets
+            .values_mut()
+            .find(|m| m.contains(order_id))
+            .and_then(|m| m.cancel(order_id, RejectReason::Requested))
+            .ok_or_else(|| {
+                gemini_error(
+                    RejectReason::OrderNotFound,
+                    format!("Order {} not found", order_id),
+                )
+            })?;
+        self.publish(vec![order.status(OrderEventType::Cancelled, None)]);
+        Ok(order.response())
+    }
+
+    fn apply(&mut self, l2: &Level2) {
+        let mut matches = Vec::new();
+        self.market(&l2.symbol.to_lowercase())
+            .apply(l2, &mut matches);
+        let events = self.statuses(matches);
+        self.publish(events);
+    }
+}
+
+/// Simulated trading account.
+///
+/// Liquidity taken by simulated orders is removed from the local
+/// book until the feed next updates that level, so the same quantity
+/// is never filled twice.
+pub struct PaperTrading {
+    inner: Mutex<Inner>,
+}
+
+impl PaperTrading {
+    /// Create an account holding the given (currency, amount)
+    /// balances.
+    pub fn new(balances: &[(&str, f64)], fees: PaperFees) -> PaperTrading {
+        let inner = Inner {
+            fees,
+            balances: balances
+                .iter()
+                .map(|(c, a)| (c.to_uppercase(), *a))
+                .collect(),
+            ..Inner::default()
+        };
+        PaperTrading {
+            inner: Mutex::new(inner),
+        }
+    }
+
+    /// Apply an `l2_updates` message and match resting orders against
+    /// it.
+    pub fn apply(&self, l2: &Level2) {
+        self.inner.lock().unwrap().apply(l2);
+    }
+
+    /// Apply a message from the market data feed. Only `Level2`
+    /// messages affect the simulation.
+    pub fn apply_message(&self, msg: &MarketDataMessage) {
+        if let MarketDataMessage::Level2(l2) = msg {
+            self.apply(l2);
+        }
+    }
+
+    /// Current local copy of the book of `symbol`, including liquidity
+    /// taken by simulated orders.
+    pub fn book(&self, symbol: &str) -> Option<OrderBook> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .markets
+            .get(&symbol.to_lowercase())
+            .map(|m| m.book().clone())
+    }
+
+    /// Order events of simulated orders, in the format of the order
+    /// events feed.
+    pub fn events(&self) -> UnboundedReceiverStream<OrderMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.inner.lock().unwrap().subscribers.push(tx);
+        UnboundedReceiverStream::new(rx)
+    }
+}
+
+impl Trading for PaperTrading {
+    fn new_order(&self, order: &Order) -> BoxFuture<'_, Result<OrderResponse>> {
+        let resp = self.inner.lock().unwrap().new_order(order);
+        future::ready(resp).boxed()
+    }
+
+    fn cancel_order(&self, order_id: OrderId) -> BoxFuture<'_, Result<OrderResponse>> {
+        let resp = self.inner.lock().unwrap().cancel_order(order_id);
+        future::ready(resp).boxed()
+    }
+
+    fn cancel_all_orders(&self) -> BoxFuture<'_, Result<CancelResponse>> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut ids: Vec<OrderId> = inner.orders().map(|o| o.order_id).collect();
+        ids.sort_by_key(|id| id.0);
+        for id in &ids {
+            let _ = inner.cancel_order(*id);
+        }
+        let resp = CancelResponse {
+            result: "ok".to_string(),
+            details: CancelDetails {
+                cancel_rejects: Vec::new(),
+                cancelled_orders: ids,
+            },
+        };
+        future::ok(resp).boxed()
+    }
+
+    fn balances(&self) -> BoxFuture<'_, Result<Vec<AccountBalance>>> {
+        let inner = self.inner.lock().unwrap();
+        let balances = inner
+            .balances
+            .iter()
+            .map(|(currency, amount)| {
+                let available = decimal(inner.available(currency));
+                AccountBalance {
+                    currency: currency.clone(),
+                    amount: decimal(*amount),
+                    available: available.clone(),
+                    available_for_withdrawal: available,
+                }
+            })
+            .collect();
+        future::ok(balances).boxed()
+    }
+
+    fn recent_trades(&self, symbol: &str) -> BoxFuture<'_, Result<Vec<AccountTrade>>> {
+        let inner = self.inner.lock().unwrap();
+        let trades = inner
+            .trades
+            .get(&symbol.to_lowercase())
+            .cloned()
+            .unwrap_or_default();
+        future::ok(trades).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::order::OrderBuilder;
+    use crate::structs::wsfeed::{Level2Change, Trade};
+    use crate::types::GError;
+    use futures::StreamExt;
+
+    fn l2(changes: &[(OrderSide, &str, &str)], trades: &[(&str, &str)]) -> Level2 {
+        Level2 {
+            symbol: "BTCUSD".to_string(),
+            changes: Some(
+                changes
+                    .iter()
+                    .map(|(side, price, quantity)| Level2Change {
+                        order: *side,
+                        price: price.to_string(),
+                        quantity: quantity.to_string(),
+                    })
+                    .collect(),
+            ),
+            trades: Some(
+                trades
+                    .iter()
+                    .map(|(price, quantity)| Trade {
+                        price: price.to_string(),
+                        quantity: quantity.to_string(),
+                        side: OrderSide::Sell,
+                    })
+                    .collect(),
+            ),
+            auction_events: None,
+        }
+    }
+
+    /// Account with a book of 1 BTC at 100/101 and 2 BTC at 99/102.
+    fn paper() -> PaperTrading {
+        let fees = PaperFees {
+            maker_fee_bps: 10.0,
+            taker_fee_bps: 20.0,
+        };
+        let paper = PaperTrading::new(&[("USD", 10_000.0), ("BTC", 10.0)], fees);
+        paper.apply(&l2(
+            &[
+                (OrderSide::Buy, "100.00", "1"),
+                (OrderSide::Buy, "99.00", "2"),
+                (OrderSide::Sell, "101.00", "1"),
+                (OrderSide::Sell, "102.00", "2"),
+            ],
+            &[],
+        ));
+        paper
+    }
+
+    fn limit(side: OrderSide, amount: f64, price: f64) -> OrderBuilder {
+        OrderBuilder::limit("btcusd", side, amount, price)
+    }
+
+    async fn send(paper: &PaperTrading, order: OrderBuilder) -> Result<OrderResponse> {
+        paper.new_order(&order.build().unwrap()).await
+    }
+
+    fn usd(paper: &PaperTrading) -> f64 {
+        paper.inner.lock().unwrap().balance("USD")
+    }
+
+    #[tokio::test]
+    async fn crossing_order_takes_levels() {
+        let paper = paper();
+        let resp = send(&paper, limit(OrderSide::Buy, 1.5, 102.0))
+            .await
+            .unwrap();
+        assert!(!resp.is_live);
+        assert_eq!(resp.executed_amount, "1.5");
+        assert_eq!(
+            resp.avg_execution_price,
+            decimal((101.0 + 0.5 * 102.0) / 1.5)
+        );
+
+        let notional = 101.0 + 51.0;
+        assert!((usd(&paper) - (10_000.0 - notional * 1.002)).abs() < 1e-9);
+        let book = paper.book("btcusd").unwrap();
+        assert_eq!(book.levels(OrderSide::Sell).next().unwrap().1, "1.5");
+    }
+
+    #[tokio::test]
+    async fn immediate_or_cancel_and_fill_or_kill() {
+        let paper = paper();
+        let ioc = send(
+            &paper,
+            limit(OrderSide::Buy, 2.0, 101.0).immediate_or_cancel(),
+        )
+        .await
+        .unwrap();
+        assert!(ioc.is_cancelled);
+        assert_eq!(ioc.executed_amount, "1");
+        assert_eq!(ioc.reason, Some(RejectReason::ImmediateOrCancelWouldPost));
+
+        let fok = send(&paper, limit(OrderSide::Buy, 5.0, 102.0).fill_or_kill())
+            .await
+            .unwrap();
+        assert!(fok.is_cancelled);
+        assert_eq!(fok.executed_amount, "0");
+        assert_eq!(fok.reason, Some(RejectReason::FillOrKillWouldNotFill));
+
+        let moc = send(&paper, limit(OrderSide::Sell, 1.0, 99.0).maker_or_cancel())
+            .await
+            .unwrap();
+        assert_eq!(moc.reason, Some(RejectReason::MakerOrCancelWouldTake));
+    }
+
+    #[tokio::test]
+    async fn refused_orders_fail_like_gemini() {
+        let paper = paper();
+        let mut events = paper.events();
+        let reason = |result: Result<OrderResponse>| match result {
+            Err(GError::Gemini(e)) => e.reject_reason(),
+            other => panic!("expected a Gemini error, got {:?}", other),
+        };
+
+        let too_big = send(&paper, limit(OrderSide::Buy, 1000.0, 100.0)).await;
+        assert_eq!(reason(too_big), RejectReason::InsufficientFunds);
+        let auction = send(&paper, limit(OrderSide::Buy, 1.0, 100.0).auction_only()).await;
+        assert_eq!(reason(auction), RejectReason::InvalidOrderType);
+        let unknown = paper.cancel_order(OrderId(42)).await;
+        assert_eq!(reason(unknown), RejectReason::OrderNotFound);
+
+        drop(paper);
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn resting_order_fills_on_book_and_trades() {
+        let paper = paper();
+        let bid = send(&paper, limit(OrderSide::Buy, 2.0, 100.5))
+            .await
+            .unwrap();
+        assert!(bid.is_live);
+
+        // A trade at the order's own price does not fill it; one
+        // through it does, at the order's price.
+        paper.apply(&l2(&[], &[("100.50", "1")]));
+        assert_eq!(paper.recent_trades("btcusd").await.unwrap().len(), 0);
+        paper.apply(&l2(&[], &[("100.00", "0.5")]));
+        let trades = paper.recent_trades("btcusd").await.unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price, "100.50");
+        assert_eq!(trades[0].amount, "0.5");
+        assert!(!trades[0].aggressor);
+
+        // The ask moving through the bid fills the rest.
+        paper.apply(&l2(&[(OrderSide::Sell, "100.40", "3")], &[]));
+        let trades = paper.recent_trades("btcusd").await.unwrap();
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].amount, "1.5");
+        assert!(paper.inner.lock().unwrap().orders().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn stop_limit_triggers_on_trade() {
+        let paper = paper();
+        let stop = OrderBuilder::stop_limit("btcusd", OrderSide::Buy, 1.0, 103.0, 104.0);
+        let resp = send(&paper, stop).await.unwrap();
+        assert!(resp.is_live);
+        assert_eq!(resp.executed_amount, "0");
+
+        paper.apply(&l2(
+            &[(OrderSide::Sell, "103.00", "1")],
+            &[("103.00", "0.1")],
+        ));
+        let trades = paper.recent_trades("btcusd").await.unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price, "101.00");
+    }
+}
@@ -0,0 +1,375 @@
+//! Realized and unrealized profit and loss.
+//!
+//! `PnlTracker` accepts historical `AccountTrade`s and live `Fill`s
+//! and matches closing trades against open lots using the chosen
+//! `CostBasis`. PnL is expressed in the quote currency of each symbol.
+//!
+//! Fees paid in the quote currency are accumulated as-is. Fees paid in
+//! the base currency reduce the position, as if the fee amount was
+//! sold at the trade price, and are valued at that price.
+use crate::book::OrderBook;
+use crate::matching::EPSILON;
+use crate::public::Ticker;
+use crate::structs::order::{Order, OrderSide};
+use crate::structs::private::AccountTrade;
+use crate::structs::wsfeed::{Fill, OrderMessage};
+use crate::util::parse_f64;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Method used to match closing trades against open lots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CostBasis {
+    /// Close the oldest lots first.
+    Fifo,
+    /// Close the newest lots first.
+    Lifo,
+    /// Keep a single lot at the average cost of the position.
+    AverageCost,
+}
+
+/// Open quantity acquired at a single price. The quantity is negative
+/// for short positions.
+#[derive(Debug, Clone, Copy)]
+struct Lot {
+    quantity: f64,
+    price: f64,
+}
+
+/// Position and PnL of a single symbol.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolPnl {
+    lots: VecDeque<Lot>,
+    realized: f64,
+    fees: f64,
+    volume: f64,
+}
+
+impl SymbolPnl {
+    /// Net position in the base currency; negative when short.
+    pub fn position(&self) -> f64 {
+        self.lots.iter().map(|l| l.quantity).sum()
+    }
+
+    /// Average entry price of the open position.
+    pub fn average_cost(&self) -> Option<f64> {
+        let position = self.position();
+        if position == 0.0 {
+            return None;
+        }
+        let cost: f64 = self.lots.iter().map(|l| l.quantity * l.price).sum();
+        Some(cost / position)
+    }
+
+    /// Realized PnL before fees.
+    pub fn realized(&self) -> f64 {
+        self.realized
+    }
+
+    /// Total fees, in the quote currency.
+    pub fn fees(&self) -> f64 {
+        self.fees
+    }
+
+    /// Realized PnL after fees.
+    pub fn net_realized(&self) -> f64 {
+        self.realized - self.fees
+    }
+
+    /// Total traded quantity, in the base currency.
+    pub fn volume(&self) -> f64 {
+        self.volume
+    }
+
+    /// PnL of the open position if it were closed at `mark`.
+    pub fn unrealized(&self, mark: f64) -> f64 {
+        self.lots
+            .iter()
+            .map(|l| l.quantity * (mark - l.price))
+            .sum()
+    }
+
+    /// Add `quantity` (signed) at `price`, closing opposite lots first.
+    fn trade(&mut self, method: CostBasis, mut quantity: f64, price: f64) {
+        while quantity != 0.0 {
+            let lot = match method {
+                CostBasis::Lifo => self.lots.back_mut(),
+                CostBasis::Fifo | CostBasis::AverageCost => self.lots.front_mut(),
+            };
+            let lot = match lot {
+                Some(lot) if lot.quantity.signum() != quantity.signum() => lot,
+                _ => break,
+            };
+
+            let scale = lot.quantity.abs().max(quantity.abs());
+            let closed = if lot.quantity.abs() <= quantity.abs() {
+                -lot.quantity
+            } else {
+                quantity
+            };
+            self.realized += -closed * (price - lot.price);
+            lot.quantity += closed;
+            quantity -= closed;
+            // Drop the float residue of closing equal quantities, so
+            // that neither a dust lot nor a dust position remains.
+            if quantity.abs() <= EPSILON * scale {
+                quantity = 0.0;
+            }
+            if lot.quantity.abs() <= EPSILON * scale {
+                match method {
+                    CostBasis::Lifo => self.lots.pop_back(),
+                    CostBasis::Fifo | CostBasis::AverageCost => self.lots.pop_front(),
+                };
+            }
+        }
+
+        if quantity == 0.0 {
+            return;
+        }
+        match (method, self.lots.front_mut()) {
+            (CostBasis::AverageCost, Some(lot)) => {
+                let total = lot.quantity + quantity;
+                lot.price = (lot.quantity * lot.price + quantity * price) / total;
+                lot.quantity = total;
+            }
+            _ => self.lots.push_back(Lot { quantity, price }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PnlTracker {
+    method: CostBasis,
+    symbols: HashMap<String, SymbolPnl>,
+
+    /// Trade ids already applied, so that history and live fills for
+    /// the same trade are not double counted.
+    seen: HashSet<String>,
+}
+
+impl PnlTracker {
+    pub fn new(method: CostBasis) -> PnlTracker {
+        PnlTracker {
+            method,
+            symbols: HashMap::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn method(&self) -> CostBasis {
+        self.method
+    }
+
+    /// Apply a trade on `symbol`. Returns false if a trade with the
+    /// same id was already applied.
+    #[allow(clippy::too_many_arguments)]
+    pub fn apply(
+        &mut self,
+        symbol: &str,
+        trade_id: &str,
+        side: OrderSide,
+        amount: f64,
+        price: f64,
+        fee: f64,
+        fee_currency: &str,
+    ) -> bool {
+        if !self.seen.insert(trade_id.to_string()) {
+            return false;
+        }
+
+        let base = Order::currencies(symbol).map(|(base, _)| base);
+        let fee_in_base = matches!(base, Some(b) if b.eq_ignore_ascii_case(fee_currency));
+
+        let method = self.method;
+        let pnl = self.symbols.entry(symbol.to_lowercase()).or_default();
+        let quantity = match side {
+            OrderSide::Buy => amount,
+            OrderSide::Sell => -amount,
+        };
+        pnl.trade(method, quantity, price);
+        pnl.volume += amount;
+        if fee_in_base {
+            pnl.trade(method, -fee, price);
+            pnl.fees += fee * price;
+        } else {
+            pnl.fees += fee;
+        }
+        true
+    }
+
+    /// Apply a historical trade from `Private::recent_trades`.
+    pub fn apply_trade(&mut self, symbol: &str, trade: &AccountTrade) -> bool {
+        self.apply(
+            symbol,
+            &trade.tid.to_string(),
+            trade.side,
+            parse_f64(&trade.amount),
+            parse_f64(&trade.price),
+            parse_f64(&trade.fee_amount),
+            &trade.fee_currency,
+        )
+    }
+
+    /// Apply a live fill from the order events feed.
+    pub fn apply_fill(&mut self, symbol: &str, side: OrderSide, fill: &Fill) -> bool {
+        self.apply(
+            symbol,
+            &fill.trade_id,
+            side,
+            parse_f64(&fill.amount),
+            parse_f64(&fill.price),
+            parse_f64(&fill.fee),
+            &fill.fee_currency,
+        )
+    }
+
+    /// Apply every fill in a message from the order events feed.
+    pub fn apply_message(&mut self, msg: &OrderMessage) {
+        if let OrderMessage::Orders(orders) = msg {
+            for status in orders {
+                if let Some(fill) = &status.fill {
+                    self.apply_fill(&status.symbol, status.side, fill);
+                }
+            }
+        }
+    }
+
+    pub fn symbol(&self, symbol: &str) -> Option<&SymbolPnl> {
+        self.symbols.get(&symbol.to_lowercase())
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = (&String, &SymbolPnl)> {
+        self.symbols.iter()
+    }
+
+    /// Unrealized PnL of `symbol` against a mark price.
+    pub fn unrealized(&self, symbol: &str, mark: f64) -> f64 {
+        self.symbol(symbol).map_or(0.0, |p| p.unrealized(mark))
+    }
+
+    /// Unrealized PnL of `symbol` marked at the ticker's mid price.
+    pub fn unrealized_at_ticker(&self, symbol: &str, ticker: &Ticker) -> f64 {
+        self.unrealized(symbol, ticker.mid())
+    }
+
+    /// Unrealized PnL of the book's symbol marked at the book's mid
+    /// price, or `None` if either side of the book is empty.
+    pub fn unrealized_at_book(&self, book: &OrderBook) -> Option<f64> {
+        Some(self.unrealized(book.symbol(), book.mid()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::wsfeed::FillLiquidity;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    /// Buy 1 at 100 and 1 at 110, then sell 1 at 120.
+    fn tracker(method: CostBasis) -> PnlTracker {
+        let mut pnl = PnlTracker::new(method);
+        pnl.apply("btcusd", "1", OrderSide::Buy, 1.0, 100.0, 0.1, "USD");
+        pnl.apply("btcusd", "2", OrderSide::Buy, 1.0, 110.0, 0.1, "USD");
+        pnl.apply("btcusd", "3", OrderSide::Sell, 1.0, 120.0, 0.1, "USD");
+        pnl
+    }
+
+    #[test]
+    fn cost_basis_methods() {
+        for (method, realized, cost) in [
+            (CostBasis::Fifo, 20.0, 110.0),
+            (CostBasis::Lifo, 10.0, 100.0),
+            (CostBasis::AverageCost, 15.0, 105.0),
+        ] {
+            let pnl = tracker(method);
+            let btc = pnl.symbol("BTCUSD").unwrap();
+            assert!(close(btc.position(), 1.0), "{:?}", method);
+            assert!(close(btc.realized(), realized), "{:?}", method);
+            assert!(close(btc.average_cost().unwrap(), cost), "{:?}", method);
+            assert!(close(btc.unrealized(130.0), 130.0 - cost), "{:?}", method);
+            assert!(close(btc.fees(), 0.3));
+            assert!(close(btc.net_realized(), realized - 0.3));
+            assert!(close(btc.volume(), 3.0));
+        }
+    }
+
+    #[test]
+    fn closing_through_zero_opens_a_short() {
+        let mut pnl = tracker(CostBasis::Fifo);
+        pnl.apply("btcusd", "4", OrderSide::Sell, 3.0, 90.0, 0.0, "USD");
+        let btc = pnl.symbol("btcusd").unwrap();
+        assert!(close(btc.position(), -2.0));
+        assert!(close(btc.realized(), 0.0));
+        assert!(close(btc.average_cost().unwrap(), 90.0));
+        assert!(close(btc.unrealized(80.0), 20.0));
+
+        pnl.apply("btcusd", "5", OrderSide::Buy, 2.0, 85.0, 0.0, "USD");
+        let btc = pnl.symbol("btcusd").unwrap();
+        assert_eq!(btc.position(), 0.0);
+        assert_eq!(btc.average_cost(), None);
+        assert!(close(btc.realized(), 10.0));
+    }
+
+    #[test]
+    fn closing_large_quantities_leaves_no_dust() {
+        for method in [CostBasis::Fifo, CostBasis::Lifo, CostBasis::AverageCost] {
+            let mut pnl = PnlTracker::new(method);
+            pnl.apply(
+                "shibusd",
+                "1",
+                OrderSide::Buy,
+                100000.1,
+                0.00001,
+                0.0,
+                "USD",
+            );
+            pnl.apply(
+                "shibusd",
+                "2",
+                OrderSide::Buy,
+                200000.2,
+                0.00001,
+                0.0,
+                "USD",
+            );
+            pnl.apply(
+                "shibusd",
+                "3",
+                OrderSide::Sell,
+                300000.3,
+                0.00002,
+                0.0,
+                "USD",
+            );
+            let shib = pnl.symbol("shibusd").unwrap();
+            assert_eq!(shib.position(), 0.0, "{:?}", method);
+            assert_eq!(shib.average_cost(), None, "{:?}", method);
+            assert!(close(shib.realized(), 3.000003), "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn duplicate_trades_and_base_fees() {
+        let mut pnl = PnlTracker::new(CostBasis::AverageCost);
+        let fill = Fill {
+            trade_id: "7".to_string(),
+            liquidity: FillLiquidity::Taker,
+            price: "100".to_string(),
+            amount: "1".to_string(),
+            fee: "0.01".to_string(),
+            fee_currency: "BTC".to_string(),
+        };
+        assert!(pnl.apply_fill("btcusd", OrderSide::Buy, &fill));
+        assert!(!pnl.apply_fill("btcusd", OrderSide::Buy, &fill));
+        assert!(!pnl.apply("btcusd", "7", OrderSide::Buy, 1.0, 100.0, 0.0, "USD"));
+
+        let btc = pnl.symbol("btcusd").unwrap();
+        assert!(close(btc.position(), 0.99));
+        assert!(close(btc.fees(), 1.0));
+        assert!(close(btc.volume(), 1.0));
+        assert!(close(pnl.unrealized("btcusd", 110.0), 9.9));
+        assert_eq!(pnl.unrealized("ethusd", 110.0), 0.0);
+    }
+}
@@ -2,16 +2,23 @@
 use super::structs::order::{Order, OrderId, OrderResponse};
 use super::structs::private::{AccountBalance, AccountTrade, CancelRequest, PastTrades, Payload};
 use crate::{
-    structs::private::{CancelResponse, NotionalVolume},
+    kill_switch::{KillReport, KillSwitch},
+    public::FeePromos,
+    structs::private::{
+        CancelResponse, CustodyAccountFeesRequest, CustodyFeeTransfer, FeeSchedule, FundingPayment,
+        FundingPaymentRequest, MarginAccount, NotionalVolume, Position, RiskStats, SymbolRequest,
+    },
     types::{GError, Response, Result},
 };
 use crypto::{hmac::Hmac, mac::Mac, sha2::Sha384};
+use futures::future::{self, Either};
 use futures::Future;
 use hex::ToHex;
 use hyper::client::HttpConnector;
 use hyper::{body::to_bytes, Body, Client, Request, Uri};
 use hyper_tls::HttpsConnector;
 use serde::Serialize;
+use std::collections::HashMap;
 use std::time::Duration;
 
 pub struct Private {
@@ -19,11 +26,15 @@ pub struct Private {
     api_key: String,
     api_secret: String,
     client: Client<HttpsConnector<HttpConnector>>,
+    kill_switch: KillSwitch,
 }
 
 impl Private {
     pub const USER_AGENT: &'static str = concat!("demo-gemini-client/", env!("CARGO_PKG_VERSION"));
 
+    /// Number of times `kill` retries orders that failed to cancel.
+    pub const KILL_RETRIES: u32 = 3;
+
     pub fn new(uri: &str, api_key: &str, api_secret: &str) -> Private {
         let https = HttpsConnector::new();
         let client = Client::builder()
@@ -35,6 +46,7 @@ impl Private {
             api_key: api_key.to_string(),
             api_secret: api_secret.to_string(),
             client,
+            kill_switch: KillSwitch::new(),
         }
     }
 
@@ -72,6 +84,14 @@ impl Private {
         req.body(Body::empty()).unwrap()
     }
 
+    /// Create an unauthenticated request for a public endpoint.
+    fn public_request(&self, uri: &str) -> Request<Body> {
+        let uri: Uri = (self.uri.to_string() + uri).parse().unwrap();
+
+        let req = Request::get(uri).header("User-Agent", Self::USER_AGENT);
+        req.body(Body::empty()).unwrap()
+    }
+
     /// Lifted pretty directly from coinbase-pro-rs
     pub(crate) fn call_future<U>(
         &self,
@@ -110,6 +130,28 @@ impl Private {
         self.call_future(req)
     }
 
+    /// Fees for every symbol, based on the account's current fee tier,
+    /// with the symbols that have an active fee promotion flagged.
+    pub fn fee_schedule(&self) -> impl Response<HashMap<String, FeeSchedule>> {
+        let volume = self.notional_volume();
+        let promos = self.call_future::<FeePromos>(self.public_request("/v1/feepromos"));
+        let symbols = self.call_future::<Vec<String>>(self.public_request("/v1/symbols"));
+        async move {
+            let (volume, promos, symbols) = futures::try_join!(volume, promos, symbols)?;
+            Ok(symbols
+                .into_iter()
+                .map(|symbol| {
+                    let promo = promos
+                        .symbols
+                        .iter()
+                        .any(|p| p.eq_ignore_ascii_case(&symbol));
+                    let fees = FeeSchedule::new(&symbol, &volume, promo);
+                    (symbol, fees)
+                })
+                .collect())
+        }
+    }
+
     /// Return a list of recent trades.
     pub fn recent_trades(&self, symbol: &str) -> impl Response<Vec<AccountTrade>> {
         let pt = Payload::wrap(
@@ -123,10 +165,16 @@ impl Private {
     }
 
     /// Send a new order.
+    ///
+    /// Fails with `GError::KillSwitchEngaged` while the kill switch is
+    /// engaged.
     pub fn new_order(&self, order: &Order) -> impl Response<OrderResponse> {
+        if self.kill_switch.is_engaged() {
+            return Either::Left(future::err(GError::KillSwitchEngaged));
+        }
         let pt = Payload::wrap("/v1/order/new", order);
         let req = self.request(&pt.request, &pt);
-        self.call_future(req)
+        Either::Right(self.call_future(req))
     }
 
     /// Cancel an order.
@@ -142,4 +190,147 @@ impl Private {
         let req = self.request(&pt.request, &pt);
         self.call_future(req)
     }
+
+    /// Open positions in perpetual contracts.
+    pub fn positions(&self) -> impl Response<Vec<Position>> {
+        let pt = Payload::empty("/v1/positions");
+        let req = self.request(&pt.request, &pt);
+        self.call_future(req)
+    }
+
+    /// Margin summary for a perpetual symbol, such as `btcgusdperp`.
+    pub fn margin(&self, symbol: &str) -> impl Response<MarginAccount> {
+        let pt = Payload::wrap(
+            "/v1/margin",
+            SymbolRequest {
+                symbol: symbol.to_string(),
+            },
+        );
+        let req = self.request(&pt.request, &pt);
+        self.call_future(req)
+    }
+
+    /// Risk statistics for a perpetual symbol.
+    pub fn risk_stats(&self, symbol: &str) -> impl Response<RiskStats> {
+        let pt = Payload::wrap(
+            "/v1/accounts/risk-stats",
+            SymbolRequest {
+                symbol: symbol.to_string(),
+            },
+        );
+        let req = self.request(&pt.request, &pt);
+        self.call_future(req)
+    }
+
+    /// Funding payments on perpetual positions, optionally restricted
+    /// to the range `[since, to]` of timestamps in seconds.
+    pub fn funding_payments(
+        &self,
+        since: Option<i64>,
+        to: Option<i64>,
+    ) -> impl Response<Vec<FundingPayment>> {
+        let pt = Payload::wrap(
+            "/v1/perpetuals/fundingPayment",
+            FundingPaymentRequest { since, to },
+        );
+        let req = self.request(&pt.request, &pt);
+        self.call_future(req)
+    }
+
+    /// Fees charged to the custody account, starting at `timestamp`
+    /// (in seconds) and returning at most `limit_transfers` records.
+    pub fn custody_account_fees(
+        &self,
+        timestamp: Option<i64>,
+        limit_transfers: Option<u32>,
+    ) -> impl Response<Vec<CustodyFeeTransfer>> {
+        let pt = Payload::wrap(
+            "/v1/custodyaccountfees",
+            CustodyAccountFeesRequest {
+                timestamp,
+                limit_transfers,
+            },
+        );
+        let req = self.request(&pt.request, &pt);
+        self.call_future(req)
+    }
+
+    /// Handle to this client's kill switch, e.g. to trigger it from
+    /// another task or to reset it.
+    pub fn kill_switch(&self) -> KillSwitch {
+        self.kill_switch.clone()
+    }
+
+    /// Engage the kill switch and cancel all open orders.
+    ///
+    /// A failed `cancel_all_orders` request, and orders it fails to
+    /// cancel, are retried with a growing delay up to
+    /// `Self::KILL_RETRIES` times; orders are retried individually
+    /// with `cancel_order`. Fails only if every `cancel_all_orders`
+    /// attempt failed. The kill switch stays engaged whatever the
+    /// outcome.
+    pub async fn kill(&self) -> Result<KillReport> {
+        self.kill_switch.trigger();
+
+        let mut attempt = 0;
+        let resp = loop {
+            match self.cancel_all_orders().await {
+                Ok(resp) => break resp,
+                Err(e) if attempt == Self::KILL_RETRIES => return Err(e),
+                Err(_) => {
+                    attempt += 1;
+                    Self::kill_backoff(attempt).await;
+                }
+            }
+        };
+        let mut report = KillReport {
+            cancelled: resp.details.cancelled_orders,
+            filled: Vec::new(),
+            failed: resp.details.cancel_rejects,
+        };
+
+        for attempt in 1..=Self::KILL_RETRIES {
+            if report.failed.is_empty() {
+                break;
+            }
+            Self::kill_backoff(attempt).await;
+
+            let mut failed = Vec::new();
+            for order_id in report.failed {
+                match self.cancel_order(order_id).await {
+                    Ok(resp) if resp.is_cancelled => report.cancelled.push(order_id),
+                    Ok(resp) if !resp.is_live => report.filled.push(order_id),
+                    _ => failed.push(order_id),
+                }
+            }
+            report.failed = failed;
+        }
+        Ok(report)
+    }
+
+    async fn kill_backoff(attempt: u32) {
+        tokio::time::sleep(Duration::from_millis(100 * attempt as u64)).await;
+    }
+
+    /// Wait for SIGINT (or, on unix, SIGTERM) and then `kill`.
+    ///
+    /// Meant to be spawned at startup by binaries that place orders.
+    /// Fails without cancelling anything if the signal handlers cannot
+    /// be installed.
+    pub async fn kill_on_signal(&self) -> Result<KillReport> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut term = signal(SignalKind::terminate()).map_err(GError::Io)?;
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => result.map_err(GError::Io)?,
+                _ = term.recv() => {}
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            tokio::signal::ctrl_c().await.map_err(GError::Io)?;
+        }
+        self.kill().await
+    }
 }
@@ -1,6 +1,7 @@
 //! Gemini client for apps
 use crate::types::{GError, Response, Result};
 use crate::util::f64_from_string;
+use chrono::NaiveDate;
 use futures::Future;
 use hyper::client::HttpConnector;
 use hyper::{body::to_bytes, Body, Client, Request, Uri};
@@ -23,14 +24,86 @@ pub struct VolumeInfo {
 #[derive(Deserialize, Debug)]
 pub struct Ticker {
     #[serde(deserialize_with = "f64_from_string")]
-    ask: f64,
+    pub ask: f64,
     #[serde(deserialize_with = "f64_from_string")]
-    bid: f64,
+    pub bid: f64,
     #[serde(deserialize_with = "f64_from_string")]
-    last: f64,
+    pub last: f64,
     //volume: VolumeInfo
 }
 
+/// Trading rules of a symbol.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SymbolDetails {
+    pub symbol: String,
+    pub base_currency: String,
+    pub quote_currency: String,
+
+    /// Smallest increment of an order amount, in the base currency.
+    pub tick_size: f64,
+
+    /// Smallest increment of an order price, in the quote currency.
+    pub quote_increment: f64,
+
+    #[serde(deserialize_with = "f64_from_string")]
+    pub min_order_size: f64,
+    pub status: String,
+}
+
+impl SymbolDetails {
+    /// Number of decimal places of order (amounts, prices), as taken
+    /// by `OrderBuilder::precision`.
+    pub fn precision(&self) -> (usize, usize) {
+        (decimals(self.tick_size), decimals(self.quote_increment))
+    }
+}
+
+/// Number of decimal places needed to express multiples of
+/// `increment`, e.g. 2 for 0.01 and 1 for 0.5.
+fn decimals(increment: f64) -> usize {
+    if !(increment > 0.0 && increment < 1.0) {
+        return 0;
+    }
+    (-increment.log10() - 1e-9).ceil() as usize
+}
+
+impl Ticker {
+    /// Midpoint between the bid and ask.
+    pub fn mid(&self) -> f64 {
+        (self.bid + self.ask) / 2.0
+    }
+}
+
+/// Current and estimated funding amount for a perpetual contract.
+///
+/// Funding updates of the market data feed are
+/// `structs::wsfeed::FundingAmount`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FundingAmountInfo {
+    pub symbol: String,
+    pub funding_date_time: String,
+    pub funding_timestamp_milli_secs: u64,
+    pub next_funding_timestamp: u64,
+    #[serde(deserialize_with = "f64_from_string")]
+    pub amount: f64,
+    #[serde(deserialize_with = "f64_from_string")]
+    pub estimated_funding_amount: f64,
+}
+
+/// Networks on which a token can be deposited or withdrawn.
+#[derive(Deserialize, Debug)]
+pub struct Network {
+    pub token: String,
+    pub network: Vec<String>,
+}
+
+/// Symbols that currently have fee promotions.
+#[derive(Deserialize, Debug)]
+pub struct FeePromos {
+    pub symbols: Vec<String>,
+}
+
 impl Public {
     pub const USER_AGENT: &'static str = concat!("demo-gemini-client/", env!("CARGO_PKG_VERSION"));
 
@@ -79,15 +152,84 @@ impl Public {
         }
     }
 
+    /// Return the raw body of the response, without attempting to
+    /// deserialize it.
+    pub(crate) fn call_bytes(&self, request: Request<Body>) -> impl Response<Vec<u8>> {
+        let res = self.client.request(request);
+        async move {
+            let res = res.await.map_err(GError::Http)?;
+            let status = res.status();
+            let body = to_bytes(res.into_body()).await.map_err(GError::Http)?;
+            if !status.is_success() {
+                let data = String::from_utf8_lossy(&body).into_owned();
+                return Err(serde_json::from_slice(&body)
+                    .map(GError::Gemini)
+                    .unwrap_or_else(|error| GError::SerdeDe { error, data }));
+            }
+            Ok(body.to_vec())
+        }
+    }
+
     /// Get ticker information for a product such as BTCUSD
     pub fn get_symbols(&self) -> impl Response<Vec<String>> {
         let req = self.request("/v1/symbols");
         self.call_future(req)
     }
 
+    /// Get the trading rules of a symbol such as BTCUSD.
+    pub fn get_symbol_details(&self, symbol: &str) -> impl Response<SymbolDetails> {
+        let req = self.request(&format!("/v1/symbols/details/{}", symbol));
+        self.call_future(req)
+    }
+
     /// Get ticker information for a product such as BTCUSD
     pub fn get_ticker(&self, product: &str) -> impl Response<Ticker> {
         let req = self.request(&format!("/v1/pubticker/{}", product));
         self.call_future(req)
     }
+
+    /// Get the current funding amount for a perpetual contract such
+    /// as BTCGUSDPERP.
+    pub fn get_funding_amount(&self, symbol: &str) -> impl Response<FundingAmountInfo> {
+        let req = self.request(&format!("/v1/fundingamount/{}", symbol));
+        self.call_future(req)
+    }
+
+    /// Download the funding amount report for a perpetual contract as
+    /// an xlsx spreadsheet.
+    ///
+    /// The report covers `[from_date, to_date]` when both are given;
+    /// otherwise the most recent `num_rows` records are returned.
+    pub fn get_funding_amount_report(
+        &self,
+        symbol: &str,
+        from_date: Option<NaiveDate>,
+        to_date: Option<NaiveDate>,
+        num_rows: Option<u32>,
+    ) -> impl Response<Vec<u8>> {
+        let mut uri = format!("/v1/fundingamountreport/records.xlsx?symbol={}", symbol);
+        if let Some(from_date) = from_date {
+            uri += &format!("&fromDate={}", from_date.format("%Y-%m-%d"));
+        }
+        if let Some(to_date) = to_date {
+            uri += &format!("&toDate={}", to_date.format("%Y-%m-%d"));
+        }
+        if let Some(num_rows) = num_rows {
+            uri += &format!("&numRows={}", num_rows);
+        }
+        let req = self.request(&uri);
+        self.call_bytes(req)
+    }
+
+    /// Get the supported networks for a token such as BTC.
+    pub fn network(&self, token: &str) -> impl Response<Network> {
+        let req = self.request(&format!("/v1/network/{}", token));
+        self.call_future(req)
+    }
+
+    /// Get the symbols that currently have fee promotions.
+    pub fn get_fee_promos(&self) -> impl Response<FeePromos> {
+        let req = self.request("/v1/feepromos");
+        self.call_future(req)
+    }
 }
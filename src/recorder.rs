@@ -0,0 +1,449 @@
+//! Recording of raw websocket feeds to disk.
+//!
+//! A `Recorder` writes every text message received on a feed to
+//! newline-delimited JSON files, one `RecordedMessage` per line, as
+//! the raw payload together with the local time it was received.
+//! Files are rotated by size and/or age, and can be compressed with
+//! gzip (feature `gzip`) or zstd (feature `zstd`).
+//!
+//! Recording happens on a dedicated thread so that disk I/O never
+//! stalls the feed. Attach a recorder with
+//! `WSFeed::connect_public_data_recorded` or
+//! `WSFeed::connect_private_order_events_recorded`.
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// One line of a recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// Local receive time, in milliseconds since the epoch.
+    pub received: i64,
+
+    /// Raw text of the websocket message.
+    pub payload: String,
+}
+
+/// Compression of recording files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// File name extension, including the `.ndjson` part.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "ndjson",
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => "ndjson.gz",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "ndjson.zst",
+        }
+    }
+
+    fn encoder(self, file: File) -> io::Result<Encoder> {
+        Ok(match self {
+            Compression::None => Encoder::None(file),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+}
+
+/// Writer of a recording file, finished explicitly so that errors
+/// writing the compression trailer are reported.
+enum Encoder {
+    None(File),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<File>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Encoder {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Encoder::None(mut file) => file.flush(),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.finish()?.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(file) => file.write(buf),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(file) => file.flush(),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Where and how a `Recorder` writes its files.
+#[derive(Debug, Clone)]
+pub struct RecorderConfig {
+    /// Directory of the recording files; created if missing.
+    pub dir: PathBuf,
+
+    /// Prefix of file names, e.g. `btcusd-l2`. The time the file was
+    /// opened, a sequence number and the extension are appended, so
+    /// that files sort in recording order.
+    pub prefix: String,
+
+    /// Start a new file once this many uncompressed bytes have been
+    /// written to the current one.
+    pub max_bytes: Option<u64>,
+
+    /// Start a new file once the current one is this old.
+    pub max_age: Option<Duration>,
+
+    pub compression: Compression,
+}
+
+impl RecorderConfig {
+    pub fn new<P: AsRef<Path>>(dir: P, prefix: &str) -> Self {
+        RecorderConfig {
+            dir: dir.as_ref().to_path_buf(),
+            prefix: prefix.to_string(),
+            max_bytes: None,
+            max_age: None,
+            compression: Compression::None,
+        }
+    }
+}
+
+struct Output {
+    writer: BufWriter<Encoder>,
+    opened: Instant,
+    written: u64,
+}
+
+/// Writer thread state.
+struct Files {
+    config: RecorderConfig,
+    current: Option<Output>,
+    /// Number of files opened so far, to keep names unique.
+    opened: u64,
+}
+
+impl Files {
+    fn open(&mut self) -> io::Result<&mut Output> {
+        if self.current.is_none() {
+            fs::create_dir_all(&self.config.dir)?;
+            let name = format!(
+                "{}-{}-{:04}.{}",
+                self.config.prefix,
+                Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+                self.opened,
+                self.config.compression.extension()
+            );
+            self.opened += 1;
+            let file = File::create(self.config.dir.join(name))?;
+            self.current = Some(Output {
+                writer: BufWriter::new(self.config.compression.encoder(file)?),
+                opened: Instant::now(),
+                written: 0,
+            });
+        }
+        Ok(self.current.as_mut().unwrap())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        match self.current.take() {
+            Some(output) => output
+                .writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        let (max_bytes, max_age) = (self.config.max_bytes, self.config.max_age);
+        let output = self.open()?;
+        output.writer.write_all(line)?;
+        output.written += line.len() as u64;
+
+        let full = matches!(max_bytes, Some(max) if output.written >= max);
+        let old = matches!(max_age, Some(max) if output.opened.elapsed() >= max);
+        if full || old {
+            self.close()?;
+        }
+        Ok(())
+    }
+
+    fn run(mut self, rx: mpsc::Receiver<RecordedMessage>) -> io::Result<()> {
+        let mut result = Ok(());
+        for msg in rx {
+            let mut line = serde_json::to_vec(&msg)?;
+            line.push(b'\n');
+            if let Err(e) = self.write(&line) {
+                // Drop the broken file and try a fresh one for the
+                // next message, but report the first error.
+                self.current = None;
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        self.close().and(result)
+    }
+}
+
+struct Shared {
+    tx: Mutex<Option<mpsc::Sender<RecordedMessage>>>,
+    thread: Mutex<Option<JoinHandle<io::Result<()>>>>,
+}
+
+impl Shared {
+    fn finish(&self) -> io::Result<()> {
+        self.tx.lock().unwrap().take();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => thread
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("recorder panicked"))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Handle to a recording. Clones write to the same files.
+///
+/// Call `finish` to wait until every recorded message is written and
+/// the current file is closed. Dropping the last clone only stops the
+/// recording: the writer thread closes the file in the background,
+/// without blocking the dropping task, and its errors are lost.
+#[derive(Clone)]
+pub struct Recorder {
+    shared: Arc<Shared>,
+}
+
+impl Recorder {
+    pub fn new(config: RecorderConfig) -> io::Result<Recorder> {
+        fs::create_dir_all(&config.dir)?;
+        let (tx, rx) = mpsc::channel();
+        let files = Files {
+            config,
+            current: None,
+            opened: 0,
+        };
+        let thread = thread::Builder::new()
+            .name("gemini-recorder".to_string())
+            .spawn(move || files.run(rx))?;
+        Ok(Recorder {
+            shared: Arc::new(Shared {
+                tx: Mutex::new(Some(tx)),
+                thread: Mutex::new(Some(thread)),
+            }),
+        })
+    }
+
+    /// Record a message received now.
+    pub fn record(&self, payload: &str) {
+        self.record_at(Utc::now().timestamp_millis(), payload);
+    }
+
+    /// Record a message received at `received` milliseconds since the
+    /// epoch. Messages recorded after `finish` are dropped.
+    pub fn record_at(&self, received: i64, payload: &str) {
+        if let Some(tx) = self.shared.tx.lock().unwrap().as_ref() {
+            let _ = tx.send(RecordedMessage {
+                received,
+                payload: payload.to_string(),
+            });
+        }
+    }
+
+    /// Stop recording, flush and close the current file, and return
+    /// the first I/O error the recording ran into, if any.
+    pub fn finish(&self) -> io::Result<()> {
+        self.shared.finish()
+    }
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("gemini-recorder-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn messages(text: &str) -> Vec<(i64, String)> {
+        text.lines()
+            .map(|line| {
+                let msg: RecordedMessage = serde_json::from_str(line).unwrap();
+                (msg.received, msg.payload)
+            })
+            .collect()
+    }
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn record_two(compression: Compression, name: &str) -> PathBuf {
+        let dir = temp_dir(name);
+        let mut config = RecorderConfig::new(&dir, "test");
+        config.compression = compression;
+        let recorder = Recorder::new(config).unwrap();
+        recorder.record_at(1, "{\"a\":1}");
+        recorder.record_at(2, "{\"b\":2}");
+        recorder.finish().unwrap();
+        dir
+    }
+
+    fn expected_two() -> Vec<(i64, String)> {
+        vec![(1, "{\"a\":1}".to_string()), (2, "{\"b\":2}".to_string())]
+    }
+
+    #[test]
+    fn finish_writes_every_message() {
+        let dir = temp_dir("size");
+        let mut config = RecorderConfig::new(&dir, "test");
+        config.max_bytes = Some(1);
+        let recorder = Recorder::new(config).unwrap();
+        recorder.record_at(1, "{\"a\":1}");
+        recorder.record_at(2, "{\"b\":2}");
+        recorder.finish().unwrap();
+        recorder.record_at(3, "{\"c\":3}");
+        assert!(recorder.finish().is_ok());
+
+        let files = files(&dir);
+        assert_eq!(files.len(), 2);
+        let line = fs::read_to_string(&files[1]).unwrap();
+        let msg: RecordedMessage = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!((msg.received, msg.payload.as_str()), (2, "{\"b\":2}"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_by_age() {
+        let dir = temp_dir("age");
+        let mut config = RecorderConfig::new(&dir, "test");
+        config.max_age = Some(Duration::from_secs(0));
+        let recorder = Recorder::new(config).unwrap();
+        for received in 1..=3 {
+            recorder.record_at(received, "{}");
+        }
+        recorder.finish().unwrap();
+
+        let received: Vec<Vec<i64>> = files(&dir)
+            .iter()
+            .map(|f| {
+                let text = fs::read_to_string(f).unwrap();
+                messages(&text).into_iter().map(|(r, _)| r).collect()
+            })
+            .collect();
+        assert_eq!(received, vec![vec![1], vec![2], vec![3]]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drop_closes_the_file_in_the_background() {
+        let dir = temp_dir("drop");
+        let recorder = Recorder::new(RecorderConfig::new(&dir, "test")).unwrap();
+        let clone = recorder.clone();
+        recorder.record_at(1, "{\"a\":1}");
+        drop(recorder);
+        clone.record_at(2, "{\"b\":2}");
+        drop(clone);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let text = files(&dir)
+                .first()
+                .map(|f| fs::read_to_string(f).unwrap())
+                .unwrap_or_default();
+            if text.lines().count() == 2 {
+                assert_eq!(messages(&text), expected_two());
+                break;
+            }
+            assert!(Instant::now() < deadline, "recording was not written");
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_round_trip() {
+        let dir = record_two(Compression::Gzip, "gzip");
+        let files = files(&dir);
+        assert_eq!(files.len(), 1);
+        assert!(files[0].to_string_lossy().ends_with(".ndjson.gz"));
+
+        use std::io::Read;
+        let mut text = String::new();
+        flate2::read::MultiGzDecoder::new(File::open(&files[0]).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(messages(&text), expected_two());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        let dir = record_two(Compression::Zstd, "zstd");
+        let files = files(&dir);
+        assert_eq!(files.len(), 1);
+        assert!(files[0].to_string_lossy().ends_with(".ndjson.zst"));
+
+        use std::io::Read;
+        let mut text = String::new();
+        zstd::Decoder::new(File::open(&files[0]).unwrap())
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(messages(&text), expected_two());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,345 @@
+//! Replay of feeds recorded with `recorder::Recorder`.
+//!
+//! A `Replay` reads recording files in order and decodes every
+//! message exactly as the live feed would, yielding a
+//! `GeminiStream<MarketDataMessage>` or `GeminiStream<OrderMessage>`.
+//! Messages are replayed as fast as possible, or paced by their
+//! recorded receive times.
+use crate::recorder::{Compression, RecordedMessage};
+use crate::structs::wsfeed::{MarketDataMessage, OrderMessage};
+use crate::types::{GError, Result};
+use crate::wsfeed::{convert_md_msg, convert_order_msg};
+use futures::{Future, Stream};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Sleep};
+use tokio_tungstenite::tungstenite::Message as TMessage;
+
+/// Timing of a replay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    /// Yield every message as soon as it is read.
+    AsFastAsPossible,
+
+    /// Keep the recorded spacing between messages, sped up by the
+    /// given factor: `Speed(1.0)` is real time, `Speed(10.0)` ten
+    /// times faster. A speed that is not positive replays as fast as
+    /// possible.
+    Speed(f64),
+}
+
+/// Recording files in `dir` whose name starts with `prefix`, in
+/// recording order.
+pub fn recording_files<P: AsRef<Path>>(dir: P, prefix: &str) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str());
+        let matches = matches!(name, Some(n) if n.starts_with(prefix) && n.contains(".ndjson"));
+        if matches && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Open a recording file, decompressing it according to its
+/// extension.
+fn open(path: &Path) -> io::Result<Box<dyn BufRead + Send>> {
+    let file = File::open(path)?;
+    let name = path.to_string_lossy();
+    let reader: Box<dyn Read + Send> = if name.ends_with(Compression::None.extension()) {
+        Box::new(file)
+    } else if name.ends_with(".gz") {
+        #[cfg(feature = "gzip")]
+        {
+            Box::new(flate2::read::MultiGzDecoder::new(file))
+        }
+        #[cfg(not(feature = "gzip"))]
+        {
+            return Err(unsupported(path, "gzip"));
+        }
+    } else if name.ends_with(".zst") {
+        #[cfg(feature = "zstd")]
+        {
+            Box::new(zstd::Decoder::new(file)?)
+        }
+        #[cfg(not(feature = "zstd"))]
+        {
+            return Err(unsupported(path, "zstd"));
+        }
+    } else {
+        Box::new(file)
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+#[cfg(not(all(feature = "gzip", feature = "zstd")))]
+fn unsupported(path: &Path, feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{}: reading this file requires the `{}` feature",
+            path.display(),
+            feature
+        ),
+    )
+}
+
+/// Lines of a sequence of recording files.
+struct Lines {
+    files: std::vec::IntoIter<PathBuf>,
+    current: Option<Box<dyn BufRead + Send>>,
+}
+
+impl Iterator for Lines {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                match open(&self.files.next()?) {
+                    Ok(reader) => self.current = Some(reader),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            let mut line = String::new();
+            match self.current.as_mut().unwrap().read_line(&mut line) {
+                Ok(0) => self.current = None,
+                Ok(_) if line.trim().is_empty() => {}
+                Ok(_) => return Some(Ok(line)),
+                Err(e) => {
+                    self.current = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Number of lines read ahead of the stream.
+const READ_AHEAD: usize = 1024;
+
+/// Source of lines: the files until the first poll, then the channel
+/// fed by the blocking task that reads them.
+enum Source {
+    Files(Lines),
+    Reading(mpsc::Receiver<io::Result<String>>),
+}
+
+impl Source {
+    fn poll_line(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<String>>> {
+        if let Source::Files(_) = self {
+            let (tx, rx) = mpsc::channel(READ_AHEAD);
+            if let Source::Files(lines) = std::mem::replace(self, Source::Reading(rx)) {
+                tokio::task::spawn_blocking(move || {
+                    for line in lines {
+                        if tx.blocking_send(line).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        }
+        match self {
+            Source::Reading(rx) => rx.poll_recv(cx),
+            Source::Files(_) => unreachable!(),
+        }
+    }
+}
+
+/// Stream of messages decoded from recording files.
+///
+/// The files are read on a blocking task, started when the stream is
+/// first polled, so the stream must be polled within a tokio runtime.
+pub struct Replay<A> {
+    source: Source,
+    decode: fn(TMessage) -> A,
+    pacing: Pacing,
+    /// Recorded time of the first message and the instant it was
+    /// replayed.
+    start: Option<(i64, Instant)>,
+    delay: Option<(Pin<Box<Sleep>>, A)>,
+}
+
+impl<A> Replay<A> {
+    fn new<P: AsRef<Path>>(files: &[P], pacing: Pacing, decode: fn(TMessage) -> A) -> Self {
+        let files: Vec<PathBuf> = files.iter().map(|p| p.as_ref().to_path_buf()).collect();
+        Replay {
+            source: Source::Files(Lines {
+                files: files.into_iter(),
+                current: None,
+            }),
+            decode,
+            pacing,
+            start: None,
+            delay: None,
+        }
+    }
+
+    /// When a message received at `received` is due.
+    fn deadline(&mut self, received: i64) -> Option<Instant> {
+        let speed = match self.pacing {
+            Pacing::Speed(speed) if speed > 0.0 => speed,
+            _ => return None,
+        };
+        let (first, started) = *self.start.get_or_insert((received, Instant::now()));
+        let elapsed = (received - first).max(0) as f64 / 1000.0 / speed;
+        Some(started + Duration::from_secs_f64(elapsed))
+    }
+}
+
+impl Replay<MarketDataMessage> {
+    /// Replay a recording of the market data feed.
+    pub fn market_data<P: AsRef<Path>>(files: &[P], pacing: Pacing) -> Self {
+        Replay::new(files, pacing, convert_md_msg)
+    }
+}
+
+impl Replay<OrderMessage> {
+    /// Replay a recording of the order events feed.
+    pub fn order_events<P: AsRef<Path>>(files: &[P], pacing: Pacing) -> Self {
+        Replay::new(files, pacing, convert_order_msg)
+    }
+}
+
+impl<A: Unpin> Stream for Replay<A> {
+    type Item = Result<A>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some((sleep, _)) = self.delay.as_mut() {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            let (_, msg) = self.delay.take().unwrap();
+            return Poll::Ready(Some(Ok(msg)));
+        }
+
+        let line = match self.source.poll_line(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(GError::Io(e)))),
+            Poll::Ready(Some(Ok(line))) => line,
+        };
+        let recorded: RecordedMessage = match serde_json::from_str(&line) {
+            Ok(recorded) => recorded,
+            Err(error) => return Poll::Ready(Some(Err(GError::SerdeDe { error, data: line }))),
+        };
+        let msg = (self.decode)(TMessage::Text(recorded.payload));
+
+        match self.deadline(recorded.received) {
+            Some(deadline) if deadline > Instant::now() => {
+                self.delay = Some((Box::pin(tokio::time::sleep_until(deadline)), msg));
+                self.poll_next(cx)
+            }
+            _ => Poll::Ready(Some(Ok(msg))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::{Recorder, RecorderConfig};
+    use futures::StreamExt;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("gemini-replay-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn heartbeat(received: i64, sequence: u64) -> String {
+        let payload = format!(
+            "{{\"type\":\"heartbeat\",\"socket_sequence\":{}}}",
+            sequence
+        );
+        serde_json::to_string(&RecordedMessage { received, payload }).unwrap() + "\n"
+    }
+
+    fn sequence(msg: Result<MarketDataMessage>) -> u64 {
+        match msg {
+            Ok(MarketDataMessage::Heartbeat(h)) => h.socket_sequence.unwrap(),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_a_recording() {
+        let dir = temp_dir("round-trip");
+        let recorder = Recorder::new(RecorderConfig::new(&dir, "md")).unwrap();
+        recorder.record_at(1000, "{\"type\":\"heartbeat\",\"socket_sequence\":1}");
+        recorder.record_at(2000, "{\"type\":\"heartbeat\",\"socket_sequence\":2}");
+        recorder.finish().unwrap();
+
+        let files = recording_files(&dir, "md").unwrap();
+        let replay = Replay::market_data(&files, Pacing::AsFastAsPossible);
+        let sequences: Vec<u64> = replay.map(sequence).collect().await;
+        assert_eq!(sequences, vec![1, 2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speed_keeps_the_recorded_spacing() {
+        let dir = temp_dir("speed");
+        let file = dir.join("md.ndjson");
+        let lines = [heartbeat(0, 1), heartbeat(1000, 2), heartbeat(3000, 3)];
+        fs::write(&file, lines.concat()).unwrap();
+
+        let mut replay = Replay::market_data(&[&file], Pacing::Speed(2.0));
+        let start = Instant::now();
+        for (expected, offset) in [(1, 0), (2, 500), (3, 1500)] {
+            assert_eq!(sequence(replay.next().await.unwrap()), expected);
+            assert_eq!(start.elapsed(), Duration::from_millis(offset));
+        }
+        assert!(replay.next().await.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn files_are_replayed_in_name_order() {
+        let dir = temp_dir("order");
+        fs::write(dir.join("md-0001.ndjson"), heartbeat(3, 3)).unwrap();
+        fs::write(
+            dir.join("md-0000.ndjson"),
+            heartbeat(1, 1) + &heartbeat(2, 2),
+        )
+        .unwrap();
+        fs::write(dir.join("trades-0000.ndjson"), heartbeat(4, 4)).unwrap();
+        fs::write(dir.join("md-notes.txt"), "").unwrap();
+
+        let files = recording_files(&dir, "md").unwrap();
+        let names: Vec<_> = files.iter().map(|f| f.file_name().unwrap()).collect();
+        assert_eq!(names, vec!["md-0000.ndjson", "md-0001.ndjson"]);
+
+        let replay = Replay::market_data(&files, Pacing::AsFastAsPossible);
+        let sequences: Vec<u64> = replay.map(sequence).collect().await;
+        assert_eq!(sequences, vec![1, 2, 3]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn malformed_lines_are_reported_and_skipped() {
+        let dir = temp_dir("malformed");
+        let file = dir.join("md.ndjson");
+        fs::write(&file, heartbeat(1, 1) + "not json\n" + &heartbeat(2, 2)).unwrap();
+
+        let mut replay = Replay::market_data(&[&file], Pacing::AsFastAsPossible);
+        assert_eq!(sequence(replay.next().await.unwrap()), 1);
+        match replay.next().await.unwrap() {
+            Err(GError::SerdeDe { data, .. }) => assert_eq!(data, "not json\n"),
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert_eq!(sequence(replay.next().await.unwrap()), 2);
+        assert!(replay.next().await.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,593 @@
+//! Pre-trade risk checks.
+//!
+//! `RiskGate` wraps `Private` and checks every order against a set of
+//! `RiskLimits` before it is signed and sent. Orders that would break
+//! a limit fail with `GError::RiskRejected` without reaching Gemini.
+//!
+//! The gate needs to be fed reference prices (from a `Ticker` or an
+//! `OrderBook`), positions, and the order events feed to keep its view
+//! of open orders current.
+use crate::book::OrderBook;
+use crate::private::Private;
+use crate::public::Ticker;
+use crate::structs::order::{Order, OrderId, OrderResponse, OrderSide};
+use crate::structs::wsfeed::{OrderMessage, OrderStatus};
+use crate::types::{GError, Result};
+use crate::util::parse_f64;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Rule that caused an order to be rejected by a `RiskGate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RiskRule {
+    MaxNotional,
+    MaxPosition,
+    PriceBand,
+    NoReferencePrice,
+    MaxOpenOrders,
+    RateLimit,
+}
+
+impl fmt::Display for RiskRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            RiskRule::MaxNotional => "max notional",
+            RiskRule::MaxPosition => "max position",
+            RiskRule::PriceBand => "price band",
+            RiskRule::NoReferencePrice => "no reference price",
+            RiskRule::MaxOpenOrders => "max open orders",
+            RiskRule::RateLimit => "rate limit",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Limits enforced by a `RiskGate`. Every limit is optional; the
+/// default enforces nothing.
+#[derive(Debug, Clone, Default)]
+pub struct RiskLimits {
+    /// Maximum notional (amount * price) of a single order, in the
+    /// quote currency.
+    pub max_notional: Option<f64>,
+
+    /// Maximum absolute position per uppercase base currency (e.g. `BTC`),
+    /// counting the position, open orders on the same side and the
+    /// new order.
+    pub max_position: HashMap<String, f64>,
+
+    /// Maximum relative distance of an order's price from the
+    /// reference price, e.g. `0.05` for 5%.
+    pub price_band: Option<f64>,
+
+    /// Reject orders when no reference price is known for the symbol.
+    /// Only used together with `price_band`.
+    pub require_reference: bool,
+
+    /// Maximum number of open orders.
+    pub max_open_orders: Option<usize>,
+
+    /// Maximum number of orders sent within a rolling window.
+    pub rate_limit: Option<(usize, Duration)>,
+}
+
+#[derive(Debug, Clone)]
+struct OpenOrder {
+    symbol: String,
+    side: OrderSide,
+    remaining: f64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    references: HashMap<String, f64>,
+    positions: HashMap<String, f64>,
+    open_orders: HashMap<OrderId, OpenOrder>,
+
+    /// Orders seen closed, so that a REST response processed after the
+    /// order's final event cannot reopen it.
+    closed: HashSet<OrderId>,
+
+    /// Trade ids of fills already applied to `positions`.
+    trades: HashSet<String>,
+
+    /// Orders that passed the checks and are awaiting their REST
+    /// response, by reservation number.
+    in_flight: HashMap<u64, OpenOrder>,
+    next_reservation: u64,
+
+    sent: VecDeque<Instant>,
+}
+
+impl State {
+    /// Count an order that passed the checks as open until its REST
+    /// response arrives, so that concurrent orders see it.
+    fn reserve(&mut self, order: &Order) -> u64 {
+        self.next_reservation += 1;
+        let reserved = OpenOrder {
+            symbol: order.symbol().to_lowercase(),
+            side: order.side(),
+            remaining: parse_f64(order.amount()),
+        };
+        self.in_flight.insert(self.next_reservation, reserved);
+        self.next_reservation
+    }
+
+    fn open_order_count(&self) -> usize {
+        self.open_orders.len() + self.in_flight.len()
+    }
+
+    fn update_order(
+        &mut self,
+        order_id: OrderId,
+        symbol: &str,
+        side: OrderSide,
+        live: bool,
+        remaining: &str,
+    ) {
+        if self.closed.contains(&order_id) {
+            return;
+        }
+        if !live {
+            self.open_orders.remove(&order_id);
+            self.closed.insert(order_id);
+            return;
+        }
+        // Updates can arrive out of order; the remaining amount only
+        // ever shrinks.
+        let remaining = parse_f64(remaining);
+        let order = self.open_orders.entry(order_id).or_insert(OpenOrder {
+            symbol: symbol.to_lowercase(),
+            side,
+            remaining,
+        });
+        order.remaining = order.remaining.min(remaining);
+    }
+
+    fn check(&mut self, limits: &RiskLimits, order: &Order, now: Instant) -> Result<()> {
+        let symbol = order.symbol().to_lowercase();
+        let amount = parse_f64(order.amount());
+        let price = parse_f64(order.price());
+
+        if let Some(max) = limits.max_notional {
+            let notional = amount * price;
+            if notional > max {
+                return Err(reject(
+                    RiskRule::MaxNotional,
+                    format!("notional {} exceeds {}", notional, max),
+                ));
+            }
+        }
+
+        if let Some(band) = limits.price_band {
+            match self.references.get(&symbol) {
+                Some(&reference) => {
+                    let distance = (price - reference).abs() / reference;
+                    if distance > band {
+                        return Err(reject(
+                            RiskRule::PriceBand,
+                            format!(
+                                "price {} is {:.2}% from reference {}",
+                                price,
+                                distance * 100.0,
+                                reference
+                            ),
+                        ));
+                    }
+                }
+                None if limits.require_reference => {
+                    return Err(reject(
+                        RiskRule::NoReferencePrice,
+                        format!("no reference price for {}", symbol),
+                    ));
+                }
+                None => {}
+            }
+        }
+
+        if let Some((base, _)) = Order::currencies(&symbol) {
+            if let Some(&max) = limits.max_position.get(&base) {
+                let position = self.positions.get(&base).copied().unwrap_or(0.0);
+                let pending: f64 = self
+                    .open_orders
+                    .values()
+                    .chain(self.in_flight.values())
+                    .filter(|o| {
+                        o.side == order.side()
+                            && matches!(Order::currencies(&o.symbol), Some((b, _)) if b == base)
+                    })
+                    .map(|o| o.remaining)
+                    .sum();
+                let projected = match order.side() {
+                    OrderSide::Buy => position + pending + amount,
+                    OrderSide::Sell => position - pending - amount,
+                };
+                if projected.abs() > max {
+                    return Err(reject(
+                        RiskRule::MaxPosition,
+                        format!("{} position would reach {}, limit {}", base, projected, max),
+                    ));
+                }
+            }
+        }
+
+        if let Some(max) = limits.max_open_orders {
+            let open = self.open_order_count();
+            if open >= max {
+                return Err(reject(
+                    RiskRule::MaxOpenOrders,
+                    format!("{} orders open, limit {}", open, max),
+                ));
+            }
+        }
+
+        if let Some((max, window)) = limits.rate_limit {
+            while matches!(self.sent.front(), Some(t) if now.duration_since(*t) >= window) {
+                self.sent.pop_front();
+            }
+            if self.sent.len() >= max {
+                return Err(reject(
+                    RiskRule::RateLimit,
+                    format!("{} orders sent within {:?}", self.sent.len(), window),
+                ));
+            }
+            self.sent.push_back(now);
+        }
+
+        Ok(())
+    }
+}
+
+fn reject(rule: RiskRule, reason: String) -> GError {
+    GError::RiskRejected { rule, reason }
+}
+
+/// Releases an in-flight reservation, including when the request is
+/// dropped before it completes.
+struct Reservation<'a> {
+    state: &'a Mutex<State>,
+    id: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().in_flight.remove(&self.id);
+    }
+}
+
+/// `Private` client that checks orders against `RiskLimits` before
+/// sending them.
+///
+/// Without a reference price for a symbol, the price band check is
+/// skipped unless `RiskLimits::require_reference` is set.
+pub struct RiskGate {
+    private: Private,
+    limits: RiskLimits,
+    state: Mutex<State>,
+}
+
+impl RiskGate {
+    pub fn new(private: Private, limits: RiskLimits) -> RiskGate {
+        RiskGate {
+            private,
+            limits,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The wrapped client, for requests that need no checks.
+    pub fn private(&self) -> &Private {
+        &self.private
+    }
+
+    pub fn limits(&self) -> &RiskLimits {
+        &self.limits
+    }
+
+    pub fn into_inner(self) -> Private {
+        self.private
+    }
+
+    /// Check an order against the limits without sending it.
+    ///
+    /// A passing check counts towards the rate limit.
+    pub fn check(&self, order: &Order) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check(&self.limits, order, Instant::now())
+    }
+
+    /// Check an order and, if it passes, send it.
+    ///
+    /// Until its response arrives, the order counts towards the open
+    /// order and position limits of orders sent concurrently.
+    pub async fn new_order(&self, order: &Order) -> Result<OrderResponse> {
+        let _reservation = {
+            let mut state = self.state.lock().unwrap();
+            state.check(&self.limits, order, Instant::now())?;
+            Reservation {
+                state: &self.state,
+                id: state.reserve(order),
+            }
+        };
+        let resp = self.private.new_order(order).await?;
+        self.apply_response(&resp);
+        Ok(resp)
+    }
+
+    /// Cancel an order. Cancellations are never checked.
+    pub async fn cancel_order(&self, order_id: OrderId) -> Result<OrderResponse> {
+        let resp = self.private.cancel_order(order_id).await?;
+        self.apply_response(&resp);
+        Ok(resp)
+    }
+
+    /// Apply a REST response from creating or cancelling an order.
+    pub fn apply_response(&self, resp: &OrderResponse) {
+        let mut state = self.state.lock().unwrap();
+        state.update_order(
+            resp.order_id,
+            &resp.symbol,
+            resp.side,
+            resp.is_live,
+            &resp.remaining_amount,
+        );
+    }
+
+    /// Apply an order event, updating open orders and, for fills, the
+    /// position in the base currency. Each fill is applied once per
+    /// trade id.
+    pub fn apply_event(&self, status: &OrderStatus) {
+        let mut state = self.state.lock().unwrap();
+        state.update_order(
+            status.order_id,
+            &status.symbol,
+            status.side,
+            status.is_live,
+            &status.remaining_amount,
+        );
+        if let (Some(fill), Some((base, _))) = (&status.fill, Order::currencies(&status.symbol)) {
+            if !state.trades.insert(fill.trade_id.clone()) {
+                return;
+            }
+            let amount = parse_f64(&fill.amount);
+            let position = state.positions.entry(base).or_insert(0.0);
+            match status.side {
+                OrderSide::Buy => *position += amount,
+                OrderSide::Sell => *position -= amount,
+            }
+        }
+    }
+
+    /// Apply every order event in a message from the order events feed.
+    pub fn apply_message(&self, msg: &OrderMessage) {
+        if let OrderMessage::Orders(orders) = msg {
+            for status in orders {
+                self.apply_event(status);
+            }
+        }
+    }
+
+    /// Set the position in a currency such as `BTC`, e.g. from
+    /// `Private::balances` or `Private::positions`.
+    pub fn set_position(&self, currency: &str, amount: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.positions.insert(currency.to_uppercase(), amount);
+    }
+
+    pub fn position(&self, currency: &str) -> f64 {
+        let state = self.state.lock().unwrap();
+        state
+            .positions
+            .get(&currency.to_uppercase())
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    /// Set the reference price used for price band checks on `symbol`.
+    pub fn set_reference_price(&self, symbol: &str, price: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.references.insert(symbol.to_lowercase(), price);
+    }
+
+    /// Use the last trade price of a ticker as the reference price.
+    pub fn update_ticker(&self, symbol: &str, ticker: &Ticker) {
+        self.set_reference_price(symbol, ticker.last);
+    }
+
+    /// Use the mid price of a book as the reference price. Books with
+    /// an empty side are ignored.
+    pub fn update_book(&self, book: &OrderBook) {
+        if let Some(mid) = book.mid() {
+            self.set_reference_price(book.symbol(), mid);
+        }
+    }
+
+    pub fn reference_price(&self, symbol: &str) -> Option<f64> {
+        let state = self.state.lock().unwrap();
+        state.references.get(&symbol.to_lowercase()).copied()
+    }
+
+    /// Number of orders currently believed to be open, including
+    /// orders sent but not yet acknowledged.
+    pub fn open_orders(&self) -> usize {
+        self.state.lock().unwrap().open_order_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+    use crate::structs::OrderBuilder;
+
+    fn buy(amount: f64) -> Order {
+        OrderBuilder::limit("btcusd", OrderSide::Buy, amount, 100.0)
+            .build()
+            .unwrap()
+    }
+
+    fn rule<T>(result: &Result<T>) -> Option<RiskRule> {
+        match result {
+            Err(GError::RiskRejected { rule, .. }) => Some(*rule),
+            _ => None,
+        }
+    }
+
+    fn response(live: bool, remaining: &str) -> OrderResponse {
+        serde_json::from_value(serde_json::json!({
+            "order_id": "7",
+            "symbol": "btcusd",
+            "exchange": "gemini",
+            "price": "100.00",
+            "avg_execution_price": "0.00",
+            "side": "buy",
+            "type": "exchange limit",
+            "options": [],
+            "is_live": live,
+            "is_cancelled": false,
+            "executed_amount": "0",
+            "remaining_amount": remaining,
+            "original_amount": "1",
+            "is_hidden": false,
+        }))
+        .unwrap()
+    }
+
+    fn event(event: &str, live: bool, remaining: &str, trade_id: Option<&str>) -> OrderStatus {
+        let fill = trade_id.map(|trade_id| {
+            serde_json::json!({
+                "trade_id": trade_id,
+                "liquidity": "Taker",
+                "price": "100.00",
+                "amount": "0.5",
+                "fee": "0.1",
+                "fee_currency": "USD",
+            })
+        });
+        serde_json::from_value(serde_json::json!({
+            "type": event,
+            "order_id": "7",
+            "symbol": "btcusd",
+            "side": "buy",
+            "order_type": "exchange limit",
+            "timestampms": 0,
+            "is_live": live,
+            "is_cancelled": event == "cancelled",
+            "is_hidden": false,
+            "remaining_amount": remaining,
+            "original_amount": "1",
+            "price": "100.00",
+            "fill": fill,
+        }))
+        .unwrap()
+    }
+
+    async fn gate(limits: RiskLimits) -> (MockServer, RiskGate) {
+        let server = MockServer::start("key", "secret").await.unwrap();
+        let private = Private::new(&server.uri(), "key", "secret");
+        (server, RiskGate::new(private, limits))
+    }
+
+    #[tokio::test]
+    async fn concurrent_orders_count_towards_max_open_orders() {
+        let limits = RiskLimits {
+            max_open_orders: Some(2),
+            ..RiskLimits::default()
+        };
+        let (server, gate) = gate(limits).await;
+        let order = buy(0.1);
+
+        let results = futures::future::join_all((0..5).map(|_| gate.new_order(&order))).await;
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 2);
+        assert!(results
+            .iter()
+            .filter(|r| r.is_err())
+            .all(|r| rule(r) == Some(RiskRule::MaxOpenOrders)));
+        assert_eq!(server.open_orders().len(), 2);
+        assert_eq!(gate.open_orders(), 2);
+    }
+
+    #[tokio::test]
+    async fn concurrent_orders_count_towards_max_position() {
+        let mut limits = RiskLimits::default();
+        limits.max_position.insert("BTC".to_string(), 1.0);
+        let (_server, gate) = gate(limits).await;
+        gate.set_position("btc", 0.5);
+        let order = buy(0.2);
+
+        let results = futures::future::join_all((0..4).map(|_| gate.new_order(&order))).await;
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 2);
+        assert_eq!(
+            rule(&gate.new_order(&order).await),
+            Some(RiskRule::MaxPosition)
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_order_releases_its_reservation() {
+        let limits = RiskLimits {
+            max_open_orders: Some(1),
+            ..RiskLimits::default()
+        };
+        let (server, gate) = gate(limits).await;
+        server.error("/v1/order/new", 400, "InsufficientFunds", "Not enough");
+        assert!(gate.new_order(&buy(0.1)).await.is_err());
+        assert_eq!(gate.open_orders(), 0);
+
+        server.clear_fixture("/v1/order/new");
+        gate.new_order(&buy(0.1)).await.unwrap();
+        assert_eq!(
+            rule(&gate.new_order(&buy(0.1)).await),
+            Some(RiskRule::MaxOpenOrders)
+        );
+    }
+
+    #[test]
+    fn price_band_and_notional() {
+        let limits = RiskLimits {
+            max_notional: Some(50.0),
+            price_band: Some(0.05),
+            require_reference: true,
+            ..RiskLimits::default()
+        };
+        let mut state = State::default();
+        let now = Instant::now();
+        let check = |state: &mut State, amount| rule(&state.check(&limits, &buy(amount), now));
+
+        assert_eq!(check(&mut state, 0.1), Some(RiskRule::NoReferencePrice));
+        state.references.insert("btcusd".to_string(), 90.0);
+        assert_eq!(check(&mut state, 0.1), Some(RiskRule::PriceBand));
+        state.references.insert("btcusd".to_string(), 98.0);
+        assert_eq!(check(&mut state, 0.1), None);
+        assert_eq!(check(&mut state, 1.0), Some(RiskRule::MaxNotional));
+    }
+
+    #[tokio::test]
+    async fn late_ack_does_not_reopen_a_closed_order() {
+        let (_server, gate) = gate(RiskLimits::default()).await;
+        gate.apply_event(&event("accepted", true, "1", None));
+        gate.apply_event(&event("cancelled", false, "1", None));
+        gate.apply_response(&response(true, "1"));
+        assert_eq!(gate.open_orders(), 0);
+    }
+
+    #[test]
+    fn late_ack_does_not_grow_a_partially_filled_order() {
+        let mut state = State::default();
+        let id = OrderId(8);
+        state.update_order(id, "btcusd", OrderSide::Buy, true, "0.5");
+        state.update_order(id, "btcusd", OrderSide::Buy, true, "1");
+        assert_eq!(state.open_orders[&id].remaining, 0.5);
+    }
+
+    #[tokio::test]
+    async fn duplicate_fills_count_once() {
+        let (_server, gate) = gate(RiskLimits::default()).await;
+        let fill = event("fill", true, "0.5", Some("1"));
+        gate.apply_event(&fill);
+        gate.apply_event(&fill);
+        gate.apply_event(&event("fill", false, "0", Some("2")));
+        assert_eq!(gate.position("BTC"), 1.0);
+    }
+}
@@ -0,0 +1,183 @@
+//! Validation of `socket_sequence` numbers on websocket feeds.
+//!
+//! Gemini numbers every message on a connection with a monotonically
+//! increasing `socket_sequence`. A missing number means a message was
+//! lost, and any state derived from the feed (e.g. open orders) must
+//! be resynchronized, for instance via the REST API.
+use crate::structs::wsfeed::OrderMessage;
+use crate::structs::wsfeed_v1::V1MarketDataMessage;
+use crate::types::{GError, Result};
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Messages carrying socket sequence numbers.
+pub trait Sequenced {
+    /// First and last sequence number in the message, or `None` if the
+    /// message is not sequenced.
+    fn sequence_range(&self) -> Option<(u64, u64)>;
+}
+
+impl Sequenced for OrderMessage {
+    fn sequence_range(&self) -> Option<(u64, u64)> {
+        match self {
+            OrderMessage::Heartbeat(h) => h.socket_sequence.map(|s| (s, s)),
+            OrderMessage::SubscriptionAck(a) => a.socket_sequence.map(|s| (s, s)),
+            OrderMessage::Orders(orders) => {
+                let mut seqs = orders.iter().filter_map(|o| o.socket_sequence);
+                let first = seqs.next()?;
+                Some((first, seqs.next_back().unwrap_or(first)))
+            }
+            OrderMessage::InternalError(_) => None,
+        }
+    }
+}
+
+impl Sequenced for V1MarketDataMessage {
+    fn sequence_range(&self) -> Option<(u64, u64)> {
+        match self {
+            V1MarketDataMessage::Update(u) => Some((u.socket_sequence, u.socket_sequence)),
+            V1MarketDataMessage::Heartbeat(h) => Some((h.socket_sequence, h.socket_sequence)),
+            V1MarketDataMessage::InternalError(_) => None,
+        }
+    }
+}
+
+/// Stream adapter that checks sequence numbers are contiguous.
+///
+/// The first sequenced message sets the baseline. On a gap, the
+/// adapter yields `GError::SequenceGap` followed by the message that
+/// revealed it, and continues checking from that message's sequence.
+pub struct SequenceCheck<S, A> {
+    stream: S,
+    last: Option<u64>,
+    pending: Option<A>,
+}
+
+impl<S, A> SequenceCheck<S, A> {
+    pub fn new(stream: S) -> Self {
+        SequenceCheck {
+            stream,
+            last: None,
+            pending: None,
+        }
+    }
+
+    /// Last sequence number seen.
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.last
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S, A> Stream for SequenceCheck<S, A>
+where
+    S: Stream<Item = Result<A>> + Unpin,
+    A: Sequenced + Unpin,
+{
+    type Item = Result<A>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(msg) = self.pending.take() {
+            return Poll::Ready(Some(Ok(msg)));
+        }
+
+        let msg = match futures::ready!(self.stream.poll_next_unpin(cx)) {
+            Some(Ok(msg)) => msg,
+            other => return Poll::Ready(other),
+        };
+        let (first, last) = match msg.sequence_range() {
+            Some(range) => range,
+            None => return Poll::Ready(Some(Ok(msg))),
+        };
+
+        let previous = self.last.replace(last);
+        match previous {
+            Some(previous) if first != previous + 1 => {
+                self.pending = Some(msg);
+                Poll::Ready(Some(Err(GError::SequenceGap {
+                    expected: previous + 1,
+                    received: first,
+                })))
+            }
+            _ => Poll::Ready(Some(Ok(msg))),
+        }
+    }
+}
+
+/// Attach a `SequenceCheck` to a stream of sequenced messages.
+pub trait SequenceCheckExt<A: Sequenced>: Stream<Item = Result<A>> + Unpin + Sized {
+    fn check_sequence(self) -> SequenceCheck<Self, A> {
+        SequenceCheck::new(self)
+    }
+}
+
+impl<S, A> SequenceCheckExt<A> for S
+where
+    S: Stream<Item = Result<A>> + Unpin,
+    A: Sequenced,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::wsfeed::Heartbeat;
+
+    fn heartbeat(socket_sequence: Option<u64>) -> Result<OrderMessage> {
+        Ok(OrderMessage::Heartbeat(Heartbeat { socket_sequence }))
+    }
+
+    /// Sequence numbers of the messages, with gaps as `Err`.
+    async fn check(
+        sequences: Vec<Option<u64>>,
+    ) -> Vec<std::result::Result<Option<u64>, (u64, u64)>> {
+        let stream = futures::stream::iter(sequences.into_iter().map(heartbeat));
+        stream
+            .check_sequence()
+            .map(|msg| match msg {
+                Ok(OrderMessage::Heartbeat(h)) => Ok(h.socket_sequence),
+                Err(GError::SequenceGap { expected, received }) => Err((expected, received)),
+                other => panic!("unexpected message {:?}", other),
+            })
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn contiguous_sequences_pass_through() {
+        let seen = check(vec![Some(0), Some(1), None, Some(2)]).await;
+        assert_eq!(seen, vec![Ok(Some(0)), Ok(Some(1)), Ok(None), Ok(Some(2))]);
+    }
+
+    #[tokio::test]
+    async fn first_message_sets_the_baseline() {
+        let seen = check(vec![None, Some(41), Some(42)]).await;
+        assert_eq!(seen, vec![Ok(None), Ok(Some(41)), Ok(Some(42))]);
+
+        let mut stream = futures::stream::iter(vec![heartbeat(Some(7))]).check_sequence();
+        assert_eq!(stream.last_sequence(), None);
+        stream.next().await.unwrap().unwrap();
+        assert_eq!(stream.last_sequence(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn gaps_are_reported_before_the_message() {
+        let seen = check(vec![Some(1), Some(2), Some(5), Some(6), Some(6)]).await;
+        assert_eq!(
+            seen,
+            vec![
+                Ok(Some(1)),
+                Ok(Some(2)),
+                Err((3, 5)),
+                Ok(Some(5)),
+                Ok(Some(6)),
+                Err((7, 6)),
+                Ok(Some(6)),
+            ]
+        );
+    }
+}
@@ -1,6 +1,7 @@
 pub mod order;
 pub mod private;
 pub mod wsfeed;
+pub mod wsfeed_v1;
 
-pub use order::{Order, OrderSide};
+pub use order::{Order, OrderBuilder, OrderSide};
 pub use wsfeed::{MarketDataMessage, OrderMessage, OrderStatus};
@@ -1,3 +1,5 @@
+use crate::types::GError;
+use crate::util::parse_f64;
 use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer, Serialize, Serializer,
@@ -5,8 +7,8 @@ use serde::{
 use std::fmt;
 
 /// order id
-#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
-pub struct OrderId(u64);
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OrderId(pub(crate) u64);
 
 impl fmt::Display for OrderId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -99,7 +101,11 @@ where
     d.deserialize_any(LowercaseOrderSide)
 }
 
-#[derive(Debug)]
+/// Order type.
+///
+/// Perpetual contracts (e.g. `btcgusdperp`) are traded with the same
+/// order types as spot symbols.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OrderType {
     Limit,
     StopLimit,
@@ -110,21 +116,31 @@ impl Serialize for OrderType {
     where
         S: Serializer,
     {
-        serializer.serialize_str(match self {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl OrderType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
             OrderType::Limit => "exchange limit",
             OrderType::StopLimit => "exchange stop limit",
-        })
+        }
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct Order {
     price: String,
     amount: String,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_price: Option<String>,
+
     #[serde(serialize_with = "OrderSide::lowercase")]
     side: OrderSide,
     symbol: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     client_order_id: String,
     options: Vec<OrderOption>,
 
@@ -133,12 +149,88 @@ pub struct Order {
 }
 
 impl Order {
+    /// Suffix shared by all perpetual contract symbols.
+    pub const PERPETUAL_SUFFIX: &'static str = "perp";
+
+    /// Return true if the symbol names a perpetual contract rather
+    /// than a spot pair.
+    pub fn is_perpetual(symbol: &str) -> bool {
+        symbol.to_lowercase().ends_with(Self::PERPETUAL_SUFFIX)
+    }
+
+    /// Quote currencies of Gemini symbols, longest suffix first.
+    const QUOTE_CURRENCIES: &'static [&'static str] = &[
+        "gusd", "usdt", "usdc", "usd", "btc", "eth", "eur", "gbp", "sgd", "dai",
+    ];
+
+    /// Split a symbol such as `btcusd` or `btcgusdperp` into its
+    /// uppercase (base, quote) currencies.
+    pub fn currencies(symbol: &str) -> Option<(String, String)> {
+        let symbol = symbol.to_lowercase();
+        let pair = symbol
+            .strip_suffix(Self::PERPETUAL_SUFFIX)
+            .unwrap_or(&symbol);
+        Self::QUOTE_CURRENCIES.iter().find_map(|quote| {
+            let base = pair.strip_suffix(quote)?;
+            if base.is_empty() {
+                None
+            } else {
+                Some((base.to_uppercase(), quote.to_uppercase()))
+            }
+        })
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn side(&self) -> OrderSide {
+        self.side
+    }
+
+    pub fn price(&self) -> &str {
+        &self.price
+    }
+
+    pub fn amount(&self) -> &str {
+        &self.amount
+    }
+
+    pub fn stop_price(&self) -> Option<&str> {
+        self.stop_price.as_deref()
+    }
+
+    pub fn client_order_id(&self) -> &str {
+        &self.client_order_id
+    }
+
+    pub fn options(&self) -> &[OrderOption] {
+        &self.options
+    }
+
+    pub fn order_type(&self) -> OrderType {
+        self.order_type
+    }
+
+    /// Number of decimal places for the (size, price) of a symbol, for
+    /// the symbols known to this library.
+    fn precision(symbol: &str) -> Option<(usize, usize)> {
+        match symbol.to_lowercase().as_str() {
+            "btcusd" => Some((8, 2)),
+            "ltcusd" => Some((5, 2)),
+            "ethusd" => Some((6, 2)),
+            "btcgusdperp" => Some((4, 1)),
+            "ethgusdperp" => Some((3, 2)),
+            _ => None,
+        }
+    }
+
+    /// Format a (size, price), rounded to the symbol's precision when
+    /// it is known.
     fn format_prices(symbol: &str, size: f64, price: f64) -> (String, String) {
-        match symbol {
-            "btcusd" => (format!("{:.8}", size), format!("{:.2}", price)),
-            "ltcusd" => (format!("{:.5}", size), format!("{:.2}", price)),
-            "ethusd" => (format!("{:.6}", size), format!("{:.2}", price)),
-            _ => panic!("unknown symbol for formatting: {}", symbol),
+        match Self::precision(symbol) {
+            Some((sp, pp)) => (format!("{:.*}", sp, size), format!("{:.*}", pp, price)),
+            None => (size.to_string(), price.to_string()),
         }
     }
 
@@ -158,6 +250,7 @@ impl Order {
         Order {
             price: price_str,
             amount: size_str,
+            stop_price: None,
             side,
             symbol: symbol.to_string(),
             client_order_id: client_oid,
@@ -167,8 +260,310 @@ impl Order {
     }
 }
 
+/// Builder covering every order type and execution option supported
+/// by Gemini.
+///
+/// Incompatible combinations are rejected by `build` before the order
+/// is ever sent:
+///  - at most one `OrderOption` may be set
+///  - stop-limit orders accept no options
+///  - a buy stop price must be at or below the limit price, and a
+///    sell stop price at or above it
+///  - amount, price and stop price must not round to zero at the
+///    symbol's precision (see `OrderBuilder::precision`)
+///
+/// Gemini has no true market order; `OrderBuilder::market` creates an
+/// immediate-or-cancel limit order at a protective price instead.
+/// Indication-of-interest orders are hidden from the public book.
+#[derive(Debug, Clone)]
+pub struct OrderBuilder {
+    symbol: String,
+    side: OrderSide,
+    amount: f64,
+    price: f64,
+    stop_price: Option<f64>,
+    client_order_id: String,
+    options: Vec<OrderOption>,
+    order_type: OrderType,
+    /// Decimal places of (amount, price).
+    precision: Option<(usize, usize)>,
+}
+
+impl OrderBuilder {
+    fn new(symbol: &str, side: OrderSide, amount: f64, price: f64, order_type: OrderType) -> Self {
+        OrderBuilder {
+            symbol: symbol.to_string(),
+            side,
+            amount,
+            price,
+            stop_price: None,
+            client_order_id: String::new(),
+            options: Vec::new(),
+            order_type,
+            precision: None,
+        }
+    }
+
+    /// Limit order resting at `price`.
+    pub fn limit(symbol: &str, side: OrderSide, amount: f64, price: f64) -> Self {
+        Self::new(symbol, side, amount, price, OrderType::Limit)
+    }
+
+    /// Market order, implemented as an immediate-or-cancel limit
+    /// order that will not fill beyond `protection_price`.
+    pub fn market(symbol: &str, side: OrderSide, amount: f64, protection_price: f64) -> Self {
+        Self::limit(symbol, side, amount, protection_price).immediate_or_cancel()
+    }
+
+    /// Stop-limit order, which becomes a limit order at `price` once
+    /// the market trades through `stop_price`.
+    pub fn stop_limit(
+        symbol: &str,
+        side: OrderSide,
+        amount: f64,
+        stop_price: f64,
+        price: f64,
+    ) -> Self {
+        let mut builder = Self::new(symbol, side, amount, price, OrderType::StopLimit);
+        builder.stop_price = Some(stop_price);
+        builder
+    }
+
+    /// Set the client order id.
+    pub fn client_order_id(mut self, client_order_id: &str) -> Self {
+        self.client_order_id = client_order_id.to_string();
+        self
+    }
+
+    /// Round amounts and prices to the given number of decimal
+    /// places, e.g. from `SymbolDetails::precision`. Defaults to the
+    /// precision of the symbols known to this library; other symbols
+    /// are sent unrounded.
+    pub fn precision(mut self, amount_decimals: usize, price_decimals: usize) -> Self {
+        self.precision = Some((amount_decimals, price_decimals));
+        self
+    }
+
+    /// Add an execution option.
+    pub fn option(mut self, option: OrderOption) -> Self {
+        if !self.options.contains(&option) {
+            self.options.push(option);
+        }
+        self
+    }
+
+    /// Cancel the order instead of taking liquidity (post-only).
+    pub fn maker_or_cancel(self) -> Self {
+        self.option(OrderOption::MakerOrCancel)
+    }
+
+    /// Fill as much as possible immediately and cancel the rest.
+    pub fn immediate_or_cancel(self) -> Self {
+        self.option(OrderOption::ImmediateOrCancel)
+    }
+
+    /// Fill the full amount immediately or cancel the entire order.
+    pub fn fill_or_kill(self) -> Self {
+        self.option(OrderOption::FillOrKill)
+    }
+
+    /// Only participate in the next auction.
+    pub fn auction_only(self) -> Self {
+        self.option(OrderOption::AuctionOnly)
+    }
+
+    /// Hidden indication of interest for block trading.
+    pub fn indication_of_interest(self) -> Self {
+        self.option(OrderOption::IndicationOfInterest)
+    }
+
+    fn validate(&self) -> core::result::Result<(), String> {
+        if !(self.amount.is_finite() && self.amount > 0.0) {
+            return Err(format!("amount must be positive, got {}", self.amount));
+        }
+        if !(self.price.is_finite() && self.price > 0.0) {
+            return Err(format!("price must be positive, got {}", self.price));
+        }
+        if self.options.len() > 1 {
+            return Err(format!(
+                "at most one order option is allowed, got {:?}",
+                self.options
+            ));
+        }
+        if let Some(stop_price) = self.stop_price {
+            if !(stop_price.is_finite() && stop_price > 0.0) {
+                return Err(format!("stop price must be positive, got {}", stop_price));
+            }
+            if let Some(option) = self.options.first() {
+                return Err(format!("stop-limit orders do not support {:?}", option));
+            }
+            match self.side {
+                OrderSide::Buy if stop_price > self.price => {
+                    return Err(format!(
+                        "buy stop price {} is above limit price {}",
+                        stop_price, self.price
+                    ));
+                }
+                OrderSide::Sell if stop_price < self.price => {
+                    return Err(format!(
+                        "sell stop price {} is below limit price {}",
+                        stop_price, self.price
+                    ));
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate the order and produce an `Order` for
+    /// `Private::new_order`.
+    pub fn build(self) -> crate::types::Result<Order> {
+        self.validate().map_err(GError::InvalidOrder)?;
+        let (price, amount, stop_price) =
+            match self.precision.or_else(|| Order::precision(&self.symbol)) {
+                Some((sp, pp)) => (
+                    format!("{:.*}", pp, self.price),
+                    format!("{:.*}", sp, self.amount),
+                    self.stop_price.map(|p| format!("{:.*}", pp, p)),
+                ),
+                None => (
+                    self.price.to_string(),
+                    self.amount.to_string(),
+                    self.stop_price.map(|p| p.to_string()),
+                ),
+            };
+
+        // Values that round to zero at the symbol's precision would be
+        // sent as e.g. "0.00" and rejected by Gemini.
+        let mut rounded = vec![("amount", &amount), ("price", &price)];
+        if let Some(stop_price) = &stop_price {
+            rounded.push(("stop price", stop_price));
+        }
+        for (name, value) in rounded {
+            if parse_f64(value) <= 0.0 {
+                return Err(GError::InvalidOrder(format!(
+                    "{} rounds to {} at the precision of {}",
+                    name, value, self.symbol
+                )));
+            }
+        }
+
+        Ok(Order {
+            price,
+            amount,
+            stop_price,
+            side: self.side,
+            symbol: self.symbol,
+            client_order_id: self.client_order_id,
+            options: self.options,
+            order_type: self.order_type,
+        })
+    }
+}
+
+/// Reason an order was rejected, cancelled or a cancel was rejected.
+///
+/// Reasons not known to this library are kept in `Other`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    InsufficientFunds,
+    MakerOrCancelWouldTake,
+    ImmediateOrCancelWouldPost,
+    FillOrKillWouldNotFill,
+    InvalidPrice,
+    InvalidQuantity,
+    InvalidSide,
+    InvalidOrderType,
+    InvalidSymbol,
+    InvalidStopPrice,
+    InvalidStopPriceBuy,
+    InvalidStopPriceSell,
+    ExceedsPriceLimits,
+    SelfCrossPrevented,
+    MarketNotOpen,
+    AuctionNotOpen,
+    IneligibleTiming,
+    OrderNotFound,
+    Requested,
+    Other(String),
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &str {
+        match self {
+            RejectReason::InsufficientFunds => "InsufficientFunds",
+            RejectReason::MakerOrCancelWouldTake => "MakerOrCancelWouldTake",
+            RejectReason::ImmediateOrCancelWouldPost => "ImmediateOrCancelWouldPost",
+            RejectReason::FillOrKillWouldNotFill => "FillOrKillWouldNotFill",
+            RejectReason::InvalidPrice => "InvalidPrice",
+            RejectReason::InvalidQuantity => "InvalidQuantity",
+            RejectReason::InvalidSide => "InvalidSide",
+            RejectReason::InvalidOrderType => "InvalidOrderType",
+            RejectReason::InvalidSymbol => "InvalidSymbol",
+            RejectReason::InvalidStopPrice => "InvalidStopPrice",
+            RejectReason::InvalidStopPriceBuy => "InvalidStopPriceBuy",
+            RejectReason::InvalidStopPriceSell => "InvalidStopPriceSell",
+            RejectReason::ExceedsPriceLimits => "ExceedsPriceLimits",
+            RejectReason::SelfCrossPrevented => "SelfCrossPrevented",
+            RejectReason::MarketNotOpen => "MarketNotOpen",
+            RejectReason::AuctionNotOpen => "AuctionNotOpen",
+            RejectReason::IneligibleTiming => "IneligibleTiming",
+            RejectReason::OrderNotFound => "OrderNotFound",
+            RejectReason::Requested => "Requested",
+            RejectReason::Other(s) => s,
+        }
+    }
+}
+
+impl From<&str> for RejectReason {
+    fn from(s: &str) -> Self {
+        match s {
+            "InsufficientFunds" => RejectReason::InsufficientFunds,
+            "MakerOrCancelWouldTake" => RejectReason::MakerOrCancelWouldTake,
+            "ImmediateOrCancelWouldPost" => RejectReason::ImmediateOrCancelWouldPost,
+            "FillOrKillWouldNotFill" => RejectReason::FillOrKillWouldNotFill,
+            "InvalidPrice" => RejectReason::InvalidPrice,
+            "InvalidQuantity" => RejectReason::InvalidQuantity,
+            "InvalidSide" => RejectReason::InvalidSide,
+            "InvalidOrderType" => RejectReason::InvalidOrderType,
+            "InvalidSymbol" => RejectReason::InvalidSymbol,
+            "InvalidStopPrice" => RejectReason::InvalidStopPrice,
+            "InvalidStopPriceBuy" => RejectReason::InvalidStopPriceBuy,
+            "InvalidStopPriceSell" => RejectReason::InvalidStopPriceSell,
+            "ExceedsPriceLimits" => RejectReason::ExceedsPriceLimits,
+            "SelfCrossPrevented" => RejectReason::SelfCrossPrevented,
+            "MarketNotOpen" => RejectReason::MarketNotOpen,
+            "AuctionNotOpen" => RejectReason::AuctionNotOpen,
+            "IneligibleTiming" => RejectReason::IneligibleTiming,
+            "OrderNotFound" => RejectReason::OrderNotFound,
+            "Requested" => RejectReason::Requested,
+            other => RejectReason::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for RejectReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for RejectReason {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        Ok(RejectReason::from(s.as_str()))
+    }
+}
+
 /// Response from creating or cancelling an order.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct OrderResponse {
     #[serde(deserialize_with = "order_id_from_string")]
     pub order_id: OrderId,
@@ -189,7 +584,7 @@ pub struct OrderResponse {
     pub is_live: bool,
     pub is_cancelled: bool,
 
-    pub reason: Option<String>,
+    pub reason: Option<RejectReason>,
 
     pub executed_amount: String,
     pub remaining_amount: String,
@@ -197,3 +592,55 @@ pub struct OrderResponse {
 
     pub is_hidden: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::public::SymbolDetails;
+
+    #[test]
+    fn build_rejects_values_that_round_to_zero() {
+        let tiny = OrderBuilder::limit("btcusd", OrderSide::Buy, 0.000_000_001, 100.0);
+        assert!(matches!(tiny.build(), Err(GError::InvalidOrder(_))));
+        let cheap = OrderBuilder::limit("btcusd", OrderSide::Buy, 1.0, 0.004);
+        assert!(matches!(cheap.build(), Err(GError::InvalidOrder(_))));
+        let stop = OrderBuilder::stop_limit("btcusd", OrderSide::Sell, 1.0, 0.004, 0.001);
+        assert!(matches!(stop.build(), Err(GError::InvalidOrder(_))));
+
+        let order = OrderBuilder::limit("btcusd", OrderSide::Buy, 0.5, 100.004)
+            .build()
+            .unwrap();
+        assert_eq!(order.amount(), "0.50000000");
+        assert_eq!(order.price(), "100.00");
+    }
+
+    #[test]
+    fn build_rounds_to_the_given_precision() {
+        let unknown = OrderBuilder::limit("solusd", OrderSide::Buy, 1.25, 20.125)
+            .build()
+            .unwrap();
+        assert_eq!(unknown.amount(), "1.25");
+        assert_eq!(unknown.price(), "20.125");
+
+        let details: SymbolDetails = serde_json::from_value(serde_json::json!({
+            "symbol": "SOLUSD",
+            "base_currency": "SOL",
+            "quote_currency": "USD",
+            "tick_size": 1e-6,
+            "quote_increment": 0.001,
+            "min_order_size": "0.01",
+            "status": "open",
+        }))
+        .unwrap();
+        let (sp, pp) = details.precision();
+        let order = OrderBuilder::limit("solusd", OrderSide::Buy, 1.25, 20.1254)
+            .precision(sp, pp)
+            .build()
+            .unwrap();
+        assert_eq!(order.amount(), "1.250000");
+        assert_eq!(order.price(), "20.125");
+
+        let legacy = Order::limit("solusd", String::new(), OrderSide::Sell, 2.0, 20.5, false);
+        assert_eq!((legacy.amount(), legacy.price()), ("2", "20.5"));
+    }
+}
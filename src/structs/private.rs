@@ -1,6 +1,23 @@
 //! Structures used by the private REST client and authroized Websocket feeds.
 use crate::structs::order::{OrderId, OrderSide};
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Last nonce handed out, shared by all clients in the process.
+static LAST_NONCE: AtomicU64 = AtomicU64::new(0);
+
+/// Return the current time in milliseconds, bumped if needed so that
+/// nonces strictly increase even for requests within the same
+/// millisecond, as Gemini requires.
+fn next_nonce() -> u64 {
+    let now = chrono::Utc::now().timestamp_millis() as u64;
+    let prev = LAST_NONCE
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now.max(last + 1))
+        })
+        .unwrap();
+    now.max(prev + 1)
+}
 
 /// Payload directly deliverable to the Gemini API, including common
 /// wrapper fields.
@@ -15,11 +32,9 @@ pub(crate) struct Payload<T: Serialize> {
 impl<T: Serialize> Payload<T> {
     /// Return a payload wrapping a deserializable structure.
     pub fn wrap(uri: &str, x: T) -> Payload<T> {
-        let nonce: i64 = chrono::Utc::now().timestamp_millis();
-
         Payload {
             request: uri.to_string(),
-            nonce: nonce as u64,
+            nonce: next_nonce(),
             content: x,
         }
     }
@@ -44,20 +59,20 @@ pub struct AccountBalance {
 
 #[derive(Debug, Deserialize)]
 pub struct NotionalVolume {
-    date: String,
-    last_updated_ms: u64,
-    web_maker_fee_bps: u32,
-    web_taker_fee_bps: u32,
-    web_auction_fee_bps: u32,
-    api_maker_fee_bps: u32,
-    api_taker_fee_bps: u32,
-    api_auction_fee_bps: u32,
-    fix_maker_fee_bps: u32,
-    fix_taker_fee_bps: u32,
-    fix_auction_fee_bps: u32,
-    block_maker_fee_bps: u32,
-    block_taker_fee_bps: u32,
-    notional_30d_volume: u32,
+    pub date: String,
+    pub last_updated_ms: u64,
+    pub web_maker_fee_bps: u32,
+    pub web_taker_fee_bps: u32,
+    pub web_auction_fee_bps: u32,
+    pub api_maker_fee_bps: u32,
+    pub api_taker_fee_bps: u32,
+    pub api_auction_fee_bps: u32,
+    pub fix_maker_fee_bps: u32,
+    pub fix_taker_fee_bps: u32,
+    pub fix_auction_fee_bps: u32,
+    pub block_maker_fee_bps: u32,
+    pub block_taker_fee_bps: u32,
+    pub notional_30d_volume: u32,
 }
 
 #[derive(Debug, Serialize)]
@@ -70,28 +85,158 @@ pub(crate) struct CancelRequest {
     pub(crate) order_id: OrderId,
 }
 
-#[derive(Debug, Deserialize)]
+/// Trade executed for one of the account's orders.
+#[derive(Debug, Deserialize, Clone)]
 pub struct AccountTrade {
-    price: String,
+    pub price: String,
+    pub amount: String,
+    pub timestamp: u64,
+    pub timestampms: u64,
 
-    amount: String,
-    //timestamp:
     #[serde(rename = "type")]
-    side: OrderSide,
+    pub side: OrderSide,
+    pub aggressor: bool,
 
-    fee_amount: String,
-    order_id: String,
+    pub fee_currency: String,
+    pub fee_amount: String,
+    pub tid: u64,
+    pub order_id: String,
+    pub client_order_id: Option<String>,
+
+    #[serde(default)]
+    pub is_auction_fill: bool,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CancelResponse {
-    result: String,
-    details: CancelDetails,
+    pub result: String,
+    pub details: CancelDetails,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CancelDetails {
-    cancel_rejects: Vec<OrderId>,
-    cancelled_orders: Vec<OrderId>,
+    pub cancel_rejects: Vec<OrderId>,
+    pub cancelled_orders: Vec<OrderId>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SymbolRequest {
+    pub(crate) symbol: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct FundingPaymentRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) since: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) to: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CustodyAccountFeesRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) timestamp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) limit_transfers: Option<u32>,
+}
+
+/// Open position in a perpetual contract.
+#[derive(Debug, Deserialize)]
+pub struct Position {
+    pub symbol: String,
+    pub instrument_type: String,
+    pub quantity: String,
+    pub notional_value: String,
+    pub realised_pnl: String,
+    pub unrealised_pnl: String,
+    pub average_cost: String,
+    pub mark_price: String,
+}
+
+/// Margin summary of the derivatives account for a single symbol.
+#[derive(Debug, Deserialize)]
+pub struct MarginAccount {
+    pub margin_assets_value: String,
+    pub initial_margin: String,
+    pub available_margin: String,
+    pub margin_maintenance_limit: String,
+    pub leverage: String,
+    pub notional_value: String,
+    pub estimated_liquidation_price: Option<String>,
+    pub initial_margin_positions: String,
+    pub reserved_margin: String,
+    pub reserved_margin_buys: String,
+    pub reserved_margin_sells: String,
+    pub buying_power: String,
+    pub selling_power: String,
+}
+
+/// Risk statistics for a perpetual contract.
+#[derive(Debug, Deserialize)]
+pub struct RiskStats {
+    pub product_type: String,
+    pub mark_price: String,
+    pub index_price: String,
+    pub open_interest: String,
+    pub open_interest_notional: String,
+}
+
+/// Currency amount as reported by the derivatives endpoints.
+#[derive(Debug, Deserialize)]
+pub struct FundingQuantity {
+    pub currency: String,
+    pub value: String,
+}
+
+/// Hourly funding transfer credited or debited for a perpetual
+/// position.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FundingPayment {
+    pub event_type: String,
+    pub timestamp: u64,
+    pub asset_code: String,
+    pub action: String,
+    pub quantity: FundingQuantity,
+    pub instrument_symbol: String,
+}
+
+/// Fees charged on a single symbol for orders placed through the API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub symbol: String,
+    pub maker_fee_bps: u32,
+    pub taker_fee_bps: u32,
+    pub auction_fee_bps: u32,
+
+    /// True if the symbol has an active fee promotion. Gemini does
+    /// not publish the promotional rates, so the fees above are the
+    /// account's regular rates and callers decide how to treat
+    /// promotions.
+    pub promo: bool,
+}
+
+impl FeeSchedule {
+    /// Combine the account fee tier with the current fee promotions.
+    pub fn new(symbol: &str, volume: &NotionalVolume, promo: bool) -> FeeSchedule {
+        FeeSchedule {
+            symbol: symbol.to_string(),
+            maker_fee_bps: volume.api_maker_fee_bps,
+            taker_fee_bps: volume.api_taker_fee_bps,
+            auction_fee_bps: volume.api_auction_fee_bps,
+            promo,
+        }
+    }
+}
+
+/// Fee charged to a custody account.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CustodyFeeTransfer {
+    pub tx_time: u64,
+    pub fee_amount: String,
+    pub fee_currency: String,
+    pub eid: u64,
+    pub event_type: String,
 }
@@ -1,15 +1,18 @@
-use crate::structs::order::{order_id_from_string, order_side_lowercase, OrderId, OrderOption};
+use crate::structs::order::{
+    order_id_from_string, order_side_lowercase, OrderId, OrderOption, RejectReason,
+};
 use crate::structs::OrderSide;
 use crate::types::GError;
+use crate::util::string_from_number;
 use serde::Deserialize;
 use serde_tuple::Deserialize_tuple;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Trade {
-    price: String,
-    quantity: String,
+    pub price: String,
+    pub quantity: String,
     #[serde(deserialize_with = "order_side_lowercase")]
-    side: OrderSide,
+    pub side: OrderSide,
 }
 
 #[derive(Deserialize_tuple, Debug, Clone)]
@@ -20,14 +23,55 @@ pub struct Level2Change {
     pub quantity: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize_tuple, Debug, Clone)]
 pub struct Candle {
-    time: u64,
-    open: String,
-    high: String,
-    low: String,
-    close: String,
-    volume: String,
+    pub time: u64,
+    #[serde(deserialize_with = "string_from_number")]
+    pub open: String,
+    #[serde(deserialize_with = "string_from_number")]
+    pub high: String,
+    #[serde(deserialize_with = "string_from_number")]
+    pub low: String,
+    #[serde(deserialize_with = "string_from_number")]
+    pub close: String,
+    #[serde(deserialize_with = "string_from_number")]
+    pub volume: String,
+}
+
+/// Time frame covered by each candle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CandleTimeFrame {
+    OneMinute,
+    FiveMinutes,
+    FifteenMinutes,
+    ThirtyMinutes,
+    OneHour,
+    SixHours,
+    OneDay,
+}
+
+/// Body of a `candles_*_updates` message.
+#[derive(Deserialize, Debug)]
+pub struct CandleUpdates {
+    pub symbol: String,
+    pub changes: Vec<Candle>,
+}
+
+#[derive(Debug)]
+pub struct Candles {
+    pub symbol: String,
+    pub time_frame: CandleTimeFrame,
+    pub changes: Vec<Candle>,
+}
+
+impl Candles {
+    fn new(time_frame: CandleTimeFrame, updates: CandleUpdates) -> Candles {
+        Candles {
+            symbol: updates.symbol,
+            time_frame,
+            changes: updates.changes,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -41,8 +85,40 @@ pub struct Level2 {
     pub auction_events: Option<Vec<AuctionEvent>>,
 }
 
+/// Mark price of a perpetual contract at a point in time.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MarkPriceChange {
+    pub timestamp: u64,
+    pub mark_price: String,
+    pub spot_index: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
-pub struct Heartbeat {}
+pub struct MarkPrice {
+    pub symbol: String,
+    pub changes: Vec<MarkPriceChange>,
+}
+
+/// Realized and estimated funding amount of a perpetual contract.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FundingAmountChange {
+    pub timestamp: u64,
+    pub funding_date_time: Option<u64>,
+    pub funding_amount: String,
+    pub estimated_funding_amount: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FundingAmount {
+    pub symbol: String,
+    pub changes: Vec<FundingAmountChange>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Heartbeat {
+    /// Only present on order event heartbeats.
+    pub socket_sequence: Option<u64>,
+}
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type")]
@@ -50,6 +126,22 @@ pub struct Heartbeat {}
 pub enum InputMDMessage {
     L2Updates(Level2),
     Trade(Trade),
+    MarkPriceUpdates(MarkPrice),
+    FundingAmountUpdates(FundingAmount),
+    #[serde(rename = "candles_1m_updates")]
+    Candles1mUpdates(CandleUpdates),
+    #[serde(rename = "candles_5m_updates")]
+    Candles5mUpdates(CandleUpdates),
+    #[serde(rename = "candles_15m_updates")]
+    Candles15mUpdates(CandleUpdates),
+    #[serde(rename = "candles_30m_updates")]
+    Candles30mUpdates(CandleUpdates),
+    #[serde(rename = "candles_1h_updates")]
+    Candles1hUpdates(CandleUpdates),
+    #[serde(rename = "candles_6h_updates")]
+    Candles6hUpdates(CandleUpdates),
+    #[serde(rename = "candles_1d_updates")]
+    Candles1dUpdates(CandleUpdates),
     Heartbeat(Heartbeat),
 }
 
@@ -57,6 +149,9 @@ pub enum InputMDMessage {
 pub enum MarketDataMessage {
     Level2(Level2),
     Trade(Trade),
+    MarkPrice(MarkPrice),
+    FundingAmount(FundingAmount),
+    Candles(Candles),
     Heartbeat(Heartbeat),
     InternalError(GError),
 }
@@ -66,6 +161,29 @@ impl From<InputMDMessage> for MarketDataMessage {
         match im {
             InputMDMessage::L2Updates(l2) => MarketDataMessage::Level2(l2),
             InputMDMessage::Trade(t) => MarketDataMessage::Trade(t),
+            InputMDMessage::MarkPriceUpdates(m) => MarketDataMessage::MarkPrice(m),
+            InputMDMessage::FundingAmountUpdates(f) => MarketDataMessage::FundingAmount(f),
+            InputMDMessage::Candles1mUpdates(c) => {
+                MarketDataMessage::Candles(Candles::new(CandleTimeFrame::OneMinute, c))
+            }
+            InputMDMessage::Candles5mUpdates(c) => {
+                MarketDataMessage::Candles(Candles::new(CandleTimeFrame::FiveMinutes, c))
+            }
+            InputMDMessage::Candles15mUpdates(c) => {
+                MarketDataMessage::Candles(Candles::new(CandleTimeFrame::FifteenMinutes, c))
+            }
+            InputMDMessage::Candles30mUpdates(c) => {
+                MarketDataMessage::Candles(Candles::new(CandleTimeFrame::ThirtyMinutes, c))
+            }
+            InputMDMessage::Candles1hUpdates(c) => {
+                MarketDataMessage::Candles(Candles::new(CandleTimeFrame::OneHour, c))
+            }
+            InputMDMessage::Candles6hUpdates(c) => {
+                MarketDataMessage::Candles(Candles::new(CandleTimeFrame::SixHours, c))
+            }
+            InputMDMessage::Candles1dUpdates(c) => {
+                MarketDataMessage::Candles(Candles::new(CandleTimeFrame::OneDay, c))
+            }
             InputMDMessage::Heartbeat(h) => MarketDataMessage::Heartbeat(h),
         }
     }
@@ -74,11 +192,13 @@ impl From<InputMDMessage> for MarketDataMessage {
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Ack {
-    account_id: u64,
-    subscription_id: String,
+    pub account_id: u64,
+    pub subscription_id: String,
+    #[serde(rename = "socket_sequence")]
+    pub socket_sequence: Option<u64>,
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
 #[serde(rename_all = "snake_case")]
 pub enum OrderEventType {
     Initial,
@@ -91,7 +211,22 @@ pub enum OrderEventType {
     Closed,
 }
 
-#[derive(Deserialize, Debug, Clone, Copy)]
+impl OrderEventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderEventType::Initial => "initial",
+            OrderEventType::Accepted => "accepted",
+            OrderEventType::Rejected => "rejected",
+            OrderEventType::Booked => "booked",
+            OrderEventType::Fill => "fill",
+            OrderEventType::Cancelled => "cancelled",
+            OrderEventType::CancelRejected => "cancel_rejected",
+            OrderEventType::Closed => "closed",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FillLiquidity {
     Maker,
     Taker,
@@ -100,7 +235,7 @@ pub enum FillLiquidity {
     IndicatorOfInterest,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Fill {
     pub trade_id: String,
     pub liquidity: FillLiquidity,
@@ -114,7 +249,7 @@ fn zero_price() -> String {
     "0.00".to_string()
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct OrderStatus {
     #[serde(rename = "type")]
     pub event_type: OrderEventType,
@@ -152,8 +287,10 @@ pub struct OrderStatus {
     pub price: String,
     pub total_spend: Option<String>,
 
-    pub reason: Option<String>,
+    pub reason: Option<RejectReason>,
     pub fill: Option<Fill>,
+
+    pub socket_sequence: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
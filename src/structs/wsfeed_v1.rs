@@ -0,0 +1,140 @@
+//! Structures used by the legacy v1 market data websocket feed.
+use crate::types::GError;
+use serde::Deserialize;
+
+/// Side of the book a level belongs to.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BookSide {
+    Bid,
+    Ask,
+}
+
+/// Side of the maker in a trade. Auction fills have no maker.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MakerSide {
+    Bid,
+    Ask,
+    Auction,
+}
+
+/// Reason a price level changed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeReason {
+    Place,
+    Trade,
+    Cancel,
+    Initial,
+    Top,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Change {
+    pub side: BookSide,
+    pub price: String,
+    pub remaining: String,
+    pub delta: Option<String>,
+    pub reason: ChangeReason,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct V1Trade {
+    pub tid: u64,
+    pub price: String,
+    pub amount: String,
+    pub maker_side: MakerSide,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BlockTrade {
+    pub tid: Option<u64>,
+    pub price: String,
+    pub amount: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuctionOpen {
+    pub auction_open_ms: u64,
+    pub auction_time_ms: u64,
+    pub first_indicative_ms: u64,
+    pub last_cancel_time_ms: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuctionIndicative {
+    pub eid: u64,
+    pub result: String,
+    pub time_ms: u64,
+    pub highest_bid_price: Option<String>,
+    pub lowest_ask_price: Option<String>,
+    pub collar_price: Option<String>,
+    pub indicative_price: Option<String>,
+    pub indicative_quantity: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuctionResult {
+    pub eid: u64,
+    pub result: String,
+    pub time_ms: u64,
+    pub highest_bid_price: Option<String>,
+    pub lowest_ask_price: Option<String>,
+    pub collar_price: Option<String>,
+    pub auction_price: Option<String>,
+    pub auction_quantity: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum V1Event {
+    Change(Change),
+    Trade(V1Trade),
+    AuctionOpen(AuctionOpen),
+    AuctionIndicative(AuctionIndicative),
+    AuctionResult(AuctionResult),
+    BlockTrade(BlockTrade),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct V1Update {
+    pub event_id: u64,
+    #[serde(rename = "socket_sequence")]
+    pub socket_sequence: u64,
+    pub timestamp: Option<u64>,
+    pub timestampms: Option<u64>,
+    pub events: Vec<V1Event>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct V1Heartbeat {
+    pub socket_sequence: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum InputV1MarketDataMessage {
+    Update(V1Update),
+    Heartbeat(V1Heartbeat),
+}
+
+#[derive(Debug)]
+pub enum V1MarketDataMessage {
+    Update(V1Update),
+    Heartbeat(V1Heartbeat),
+    InternalError(GError),
+}
+
+impl From<InputV1MarketDataMessage> for V1MarketDataMessage {
+    fn from(im: InputV1MarketDataMessage) -> Self {
+        match im {
+            InputV1MarketDataMessage::Update(u) => V1MarketDataMessage::Update(u),
+            InputV1MarketDataMessage::Heartbeat(h) => V1MarketDataMessage::Heartbeat(h),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Trading surface shared by the live and simulated clients.
+//!
+//! Strategies written against `Trading` run unchanged against
+//! `Private` or against `paper::PaperTrading`.
+use crate::private::Private;
+use crate::structs::order::{Order, OrderId, OrderResponse};
+use crate::structs::private::{AccountBalance, AccountTrade, CancelResponse};
+use crate::types::Result;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+
+pub trait Trading: Send + Sync {
+    fn new_order(&self, order: &Order) -> BoxFuture<'_, Result<OrderResponse>>;
+
+    fn cancel_order(&self, order_id: OrderId) -> BoxFuture<'_, Result<OrderResponse>>;
+
+    fn cancel_all_orders(&self) -> BoxFuture<'_, Result<CancelResponse>>;
+
+    fn balances(&self) -> BoxFuture<'_, Result<Vec<AccountBalance>>>;
+
+    fn recent_trades(&self, symbol: &str) -> BoxFuture<'_, Result<Vec<AccountTrade>>>;
+}
+
+impl Trading for Private {
+    fn new_order(&self, order: &Order) -> BoxFuture<'_, Result<OrderResponse>> {
+        Private::new_order(self, order).boxed()
+    }
+
+    fn cancel_order(&self, order_id: OrderId) -> BoxFuture<'_, Result<OrderResponse>> {
+        Private::cancel_order(self, order_id).boxed()
+    }
+
+    fn cancel_all_orders(&self) -> BoxFuture<'_, Result<CancelResponse>> {
+        Private::cancel_all_orders(self).boxed()
+    }
+
+    fn balances(&self) -> BoxFuture<'_, Result<Vec<AccountBalance>>> {
+        Private::balances(self).boxed()
+    }
+
+    fn recent_trades(&self, symbol: &str) -> BoxFuture<'_, Result<Vec<AccountTrade>>> {
+        Private::recent_trades(self, symbol).boxed()
+    }
+}
@@ -1,3 +1,4 @@
+use crate::structs::order::RejectReason;
 use futures::Future;
 use serde::Deserialize;
 use thiserror::Error;
@@ -21,15 +22,64 @@ pub enum GError {
     #[error("Serialization error: {0}")]
     SerdeSer(#[source] serde_json::Error),
 
+    /// The tungstenite error is boxed, as it is much larger than every
+    /// other variant.
     #[error("websocket error: {0}")]
-    Websocket(#[source] tokio_tungstenite::tungstenite::Error),
+    Websocket(#[source] Box<tokio_tungstenite::tungstenite::Error>),
+
+    /// The websocket handshake was rejected. `error` holds the Gemini
+    /// rejection, e.g. `InvalidSignature` or `InvalidNonce`, when the
+    /// response body carries one.
+    #[error("websocket handshake failed with HTTP {status}: {error:?}")]
+    Handshake {
+        status: u16,
+        error: Option<GeminiResponseError>,
+    },
+
+    #[error("invalid order: {0}")]
+    InvalidOrder(String),
+
+    #[error("stale connection: no message received for {0:?}")]
+    StaleConnection(std::time::Duration),
+
+    #[error("order {order_id}: {event:?} event is invalid in state {state:?}")]
+    InvalidOrderTransition {
+        order_id: crate::structs::order::OrderId,
+        state: crate::order_manager::OrderState,
+        event: crate::structs::wsfeed::OrderEventType,
+    },
+
+    #[error("connection closed")]
+    ConnectionClosed,
+
+    #[error("sequence gap: expected {expected}, received {received}")]
+    SequenceGap { expected: u64, received: u64 },
+
+    #[error("order rejected by risk check ({rule}): {reason}")]
+    RiskRejected {
+        rule: crate::risk::RiskRule,
+        reason: String,
+    },
+
+    #[error("kill switch engaged")]
+    KillSwitchEngaged,
+
+    #[error("io error: {0}")]
+    Io(#[source] std::io::Error),
 }
 
 #[derive(Debug, Deserialize)]
 pub struct GeminiResponseError {
-    result: String,
-    reason: String,
-    message: String,
+    pub result: String,
+    pub reason: String,
+    pub message: String,
+}
+
+impl GeminiResponseError {
+    /// The reason as a `RejectReason`, as decoded from order events.
+    pub fn reject_reason(&self) -> RejectReason {
+        RejectReason::from(self.reason.as_str())
+    }
 }
 
 pub type Result<T> = core::result::Result<T, GError>;
@@ -34,6 +34,18 @@ where
     d.deserialize_any(F64InQuotes)
 }
 
+/// Parse a decimal string for local arithmetic, treating malformed
+/// values as zero.
+pub fn parse_f64(s: &str) -> f64 {
+    s.parse().unwrap_or(0.0)
+}
+
+/// Parse a decimal string, returning `None` if it is malformed or not
+/// finite.
+pub fn try_parse_f64(s: &str) -> Option<f64> {
+    s.parse().ok().filter(|x: &f64| x.is_finite())
+}
+
 #[allow(unused)]
 pub fn f64_opt_from_string<'de, D>(d: D) -> Result<Option<f64>, D::Error>
 where
@@ -41,3 +53,50 @@ where
 {
     d.deserialize_any(F64InQuotes).map(Some).or(Ok(None))
 }
+
+struct NumberAsString;
+
+impl<'de> Visitor<'de> for NumberAsString {
+    type Value = String;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number or string")
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(v.to_string())
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(v.to_string())
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(v.to_string())
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(s.to_string())
+    }
+}
+
+/// Deserialize a JSON number (or string) into its string
+/// representation, so that prices and amounts can be kept as strings.
+pub fn string_from_number<'de, D>(d: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    d.deserialize_any(NumberAsString)
+}
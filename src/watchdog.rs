@@ -0,0 +1,183 @@
+//! Detection of silent websocket connections.
+//!
+//! A half-open socket can stop delivering messages without ever
+//! producing an error. `Watchdog` wraps a feed stream and yields
+//! `GError::StaleConnection` whenever nothing, not even a heartbeat,
+//! has been received for the configured timeout.
+use crate::structs::wsfeed::{MarketDataMessage, OrderMessage};
+use crate::structs::wsfeed_v1::V1MarketDataMessage;
+use crate::types::{GError, Result};
+use crate::wsfeed::GeminiStream;
+use futures::{Future, Stream, StreamExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::Sleep;
+
+/// Feed messages that may be heartbeats.
+pub trait FeedMessage {
+    fn is_heartbeat(&self) -> bool;
+}
+
+impl FeedMessage for MarketDataMessage {
+    fn is_heartbeat(&self) -> bool {
+        matches!(self, MarketDataMessage::Heartbeat(_))
+    }
+}
+
+impl FeedMessage for OrderMessage {
+    fn is_heartbeat(&self) -> bool {
+        matches!(self, OrderMessage::Heartbeat(_))
+    }
+}
+
+impl FeedMessage for V1MarketDataMessage {
+    fn is_heartbeat(&self) -> bool {
+        matches!(self, V1MarketDataMessage::Heartbeat(_))
+    }
+}
+
+/// Stream adapter that reports prolonged silence on a feed.
+///
+/// After yielding `GError::StaleConnection` the timer restarts, so the
+/// error repeats for as long as the feed stays silent. The underlying
+/// stream is left untouched; dropping it (or reconnecting) is up to
+/// the consumer.
+pub struct Watchdog<S> {
+    stream: S,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+    last_message: Instant,
+    last_heartbeat: Option<Instant>,
+}
+
+impl<S> Watchdog<S> {
+    pub fn new(stream: S, timeout: Duration) -> Self {
+        Watchdog {
+            stream,
+            timeout,
+            deadline: Box::pin(tokio::time::sleep(timeout)),
+            last_message: Instant::now(),
+            last_heartbeat: None,
+        }
+    }
+
+    /// Time the last message of any kind was received, or the time
+    /// the watchdog was created.
+    pub fn last_message(&self) -> Instant {
+        self.last_message
+    }
+
+    /// Time the last heartbeat was received.
+    pub fn last_heartbeat(&self) -> Option<Instant> {
+        self.last_heartbeat
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn reset(&mut self) {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        self.deadline.as_mut().reset(deadline);
+    }
+}
+
+impl<S, A> Stream for Watchdog<S>
+where
+    S: Stream<Item = Result<A>> + Unpin,
+    A: FeedMessage,
+{
+    type Item = Result<A>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.stream.poll_next_unpin(cx) {
+            Poll::Ready(item) => {
+                let now = Instant::now();
+                if let Some(Ok(msg)) = &item {
+                    if msg.is_heartbeat() {
+                        self.last_heartbeat = Some(now);
+                    }
+                }
+                self.last_message = now;
+                self.reset();
+                Poll::Ready(item)
+            }
+            Poll::Pending => match self.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => {
+                    let silence = self.last_message.elapsed();
+                    self.reset();
+                    Poll::Ready(Some(Err(GError::StaleConnection(silence))))
+                }
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+/// Attach a `Watchdog` to any feed stream.
+pub trait WatchdogExt<A: FeedMessage>: GeminiStream<A> + Sized {
+    fn watchdog(self, timeout: Duration) -> Watchdog<Self> {
+        Watchdog::new(self, timeout)
+    }
+}
+
+impl<S: GeminiStream<A>, A: FeedMessage> WatchdogExt<A> for S {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::wsfeed::Heartbeat;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    fn heartbeat() -> Result<MarketDataMessage> {
+        Ok(MarketDataMessage::Heartbeat(Heartbeat {
+            socket_sequence: None,
+        }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_stream_is_stale() {
+        let start = tokio::time::Instant::now();
+        let stream = futures::stream::pending::<Result<MarketDataMessage>>();
+        let mut watchdog = stream.watchdog(Duration::from_secs(5));
+        for elapsed in [5, 10] {
+            match watchdog.next().await {
+                Some(Err(GError::StaleConnection(_))) => {}
+                other => panic!("unexpected item {:?}", other),
+            }
+            assert_eq!(start.elapsed(), Duration::from_secs(elapsed));
+        }
+        assert!(watchdog.last_heartbeat().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_reset_the_timer() {
+        let start = tokio::time::Instant::now();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for _ in 0..2 {
+                tokio::time::sleep(Duration::from_secs(3)).await;
+                tx.send(heartbeat()).unwrap();
+            }
+            // Keep the stream open but silent.
+            std::future::pending::<()>().await;
+        });
+
+        let mut watchdog = UnboundedReceiverStream::new(rx).watchdog(Duration::from_secs(5));
+        for elapsed in [3, 6] {
+            assert!(matches!(
+                watchdog.next().await,
+                Some(Ok(MarketDataMessage::Heartbeat(_)))
+            ));
+            assert_eq!(start.elapsed(), Duration::from_secs(elapsed));
+        }
+        assert!(watchdog.last_heartbeat().is_some());
+        assert!(matches!(
+            watchdog.next().await,
+            Some(Err(GError::StaleConnection(_)))
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(11));
+    }
+}
@@ -1,20 +1,43 @@
 //use async_trait::async_trait;
+use crate::recorder::Recorder;
 use crate::structs::wsfeed::{
-    InputMDMessage, InputOrderMessage, MarketDataMessage, OrderMessage, OrderStatus,
+    InputMDMessage, InputOrderMessage, MarketDataMessage, OrderEventType, OrderMessage, OrderStatus,
 };
+use crate::structs::wsfeed_v1::{InputV1MarketDataMessage, V1MarketDataMessage};
 use crate::types::GError;
 use crate::{structs::private::Payload, Private};
 use futures::{future, Sink, Stream};
 use futures_util::{sink::SinkExt, stream::TryStreamExt};
 use serde::Serialize;
-use tokio_tungstenite::{connect_async, tungstenite::Message as TMessage};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest, http::HeaderValue, Error as TError, Message as TMessage,
+    },
+};
 
 pub struct WSFeed;
 
-#[derive(Serialize, Debug, Clone, Copy)]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(rename_all = "snake_case")]
 pub enum SubscriptionType {
     L2,
+    MarkPrice,
+    FundingAmount,
+    #[serde(rename = "candles_1m")]
+    Candles1m,
+    #[serde(rename = "candles_5m")]
+    Candles5m,
+    #[serde(rename = "candles_15m")]
+    Candles15m,
+    #[serde(rename = "candles_30m")]
+    Candles30m,
+    #[serde(rename = "candles_1h")]
+    Candles1h,
+    #[serde(rename = "candles_6h")]
+    Candles6h,
+    #[serde(rename = "candles_1d")]
+    Candles1d,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -24,13 +47,60 @@ pub struct Subscription {
 }
 
 #[derive(Serialize, Debug)]
-struct Subscribe {
+pub(crate) struct Subscribe {
     #[serde(rename = "type")]
     sub_type: String,
     subscriptions: Vec<Subscription>,
 }
 
-fn convert_md_msg(msg: TMessage) -> MarketDataMessage {
+impl Subscribe {
+    pub(crate) fn subscribe_to(subscriptions: &[Subscription]) -> Subscribe {
+        Subscribe {
+            sub_type: "subscribe".to_string(),
+            subscriptions: subscriptions.to_vec(),
+        }
+    }
+
+    pub(crate) fn unsubscribe_from(subscriptions: &[Subscription]) -> Subscribe {
+        Subscribe {
+            sub_type: "unsubscribe".to_string(),
+            subscriptions: subscriptions.to_vec(),
+        }
+    }
+
+    pub(crate) fn to_message(&self) -> Result<TMessage, GError> {
+        serde_json::to_string(self)
+            .map(TMessage::text)
+            .map_err(GError::SerdeSer)
+    }
+}
+
+/// Map a failed websocket connection into a `GError`, decoding the
+/// Gemini rejection of a refused handshake.
+fn handshake_error(e: TError) -> GError {
+    match e {
+        TError::Http(resp) => GError::Handshake {
+            status: resp.status().as_u16(),
+            error: resp
+                .body()
+                .as_deref()
+                .and_then(|body| serde_json::from_slice(body).ok()),
+        },
+        e => websocket_error(e),
+    }
+}
+
+fn websocket_error(e: TError) -> GError {
+    GError::Websocket(Box::new(e))
+}
+
+fn record(recorder: Option<&Recorder>, msg: &TMessage) {
+    if let (Some(recorder), TMessage::Text(text)) = (recorder, msg) {
+        recorder.record(text);
+    }
+}
+
+pub(crate) fn convert_md_msg(msg: TMessage) -> MarketDataMessage {
     match msg {
         TMessage::Text(str) => serde_json::from_str::<InputMDMessage>(&str)
             .map(|x| x.into())
@@ -44,7 +114,21 @@ fn convert_md_msg(msg: TMessage) -> MarketDataMessage {
     }
 }
 
-fn convert_order_msg(msg: TMessage) -> OrderMessage {
+fn convert_v1_md_msg(msg: TMessage) -> V1MarketDataMessage {
+    match msg {
+        TMessage::Text(str) => serde_json::from_str::<InputV1MarketDataMessage>(&str)
+            .map(|x| x.into())
+            .unwrap_or_else(|e| {
+                V1MarketDataMessage::InternalError(GError::SerdeDe {
+                    error: e,
+                    data: str,
+                })
+            }),
+        _ => unreachable!(), // filtered in stream
+    }
+}
+
+pub(crate) fn convert_order_msg(msg: TMessage) -> OrderMessage {
     match msg {
         TMessage::Text(str) => {
             if let Ok(des) = serde_json::from_str::<InputOrderMessage>(&str) {
@@ -64,6 +148,80 @@ fn convert_order_msg(msg: TMessage) -> OrderMessage {
     }
 }
 
+/// Query options of the v1 market data feed. The defaults match
+/// Gemini's.
+#[derive(Debug, Clone)]
+pub struct V1MarketDataOptions {
+    pub heartbeat: bool,
+    pub top_of_book: bool,
+    pub bids: bool,
+    pub offers: bool,
+    pub trades: bool,
+    pub auctions: bool,
+}
+
+impl Default for V1MarketDataOptions {
+    fn default() -> Self {
+        V1MarketDataOptions {
+            heartbeat: false,
+            top_of_book: false,
+            bids: true,
+            offers: true,
+            trades: true,
+            auctions: true,
+        }
+    }
+}
+
+impl V1MarketDataOptions {
+    fn query(&self) -> String {
+        format!(
+            "heartbeat={}&top_of_book={}&bids={}&offers={}&trades={}&auctions={}",
+            self.heartbeat, self.top_of_book, self.bids, self.offers, self.trades, self.auctions
+        )
+    }
+}
+
+/// Filters for the order events feed. Empty filters match everything.
+#[derive(Debug, Clone, Default)]
+pub struct OrderEventsOptions {
+    /// Only receive events for these symbols.
+    pub symbols: Vec<String>,
+
+    /// Only receive events for orders placed by these API session keys.
+    pub api_sessions: Vec<String>,
+
+    /// Only receive these event types.
+    pub event_types: Vec<OrderEventType>,
+
+    pub heartbeat: bool,
+}
+
+impl OrderEventsOptions {
+    fn query(&self) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        for symbol in &self.symbols {
+            query.append_pair("symbolFilter", symbol);
+        }
+        for session in &self.api_sessions {
+            query.append_pair("apiSessionFilter", session);
+        }
+        for event_type in &self.event_types {
+            query.append_pair("eventTypeFilter", event_type.as_str());
+        }
+        if self.heartbeat {
+            query.append_pair("heartbeat", "true");
+        }
+
+        let query = query.finish();
+        if query.is_empty() {
+            query
+        } else {
+            format!("?{}", query)
+        }
+    }
+}
+
 pub trait GeminiStream<A: Sized>: Stream<Item = Result<A, GError>> + Unpin + Send {}
 impl<T, A> GeminiStream<A> for T where T: Stream<Item = Result<A, GError>> + Unpin + Send {}
 impl<T> GeminiSink for T where T: Sink<TMessage> + Unpin + Send {}
@@ -73,23 +231,59 @@ impl WSFeed {
     pub async fn connect_public_data(
         uri: &str,
         subscriptions: &[Subscription],
-    ) -> Result<impl GeminiStream<MarketDataMessage> + GeminiSink, GError> {
+    ) -> Result<impl GeminiStream<MarketDataMessage> + Sink<TMessage, Error = GError>, GError> {
+        Self::connect_public_data_with_recorder(uri, subscriptions, None).await
+    }
+
+    /// Connect to the market data feed, recording every raw message
+    /// received with `recorder`.
+    pub async fn connect_public_data_recorded(
+        uri: &str,
+        subscriptions: &[Subscription],
+        recorder: Recorder,
+    ) -> Result<impl GeminiStream<MarketDataMessage> + Sink<TMessage, Error = GError>, GError> {
+        Self::connect_public_data_with_recorder(uri, subscriptions, Some(recorder)).await
+    }
+
+    async fn connect_public_data_with_recorder(
+        uri: &str,
+        subscriptions: &[Subscription],
+        recorder: Option<Recorder>,
+    ) -> Result<impl GeminiStream<MarketDataMessage> + Sink<TMessage, Error = GError>, GError> {
         let url = uri.to_string() + "/v2/marketdata";
-        let sub = Subscribe {
-            sub_type: "subscribe".to_string(),
-            subscriptions: subscriptions.to_vec()
-        };
+        let sub = Subscribe::subscribe_to(subscriptions);
 
-        let (stream, _resp) = connect_async(url).await.map_err(GError::Websocket)?;
+        let (stream, _resp) = connect_async(url).await.map_err(handshake_error)?;
 
         let mut stream = stream
             .try_filter(|msg| future::ready(msg.is_text()))
+            .inspect_ok(move |msg| record(recorder.as_ref(), msg))
             .map_ok(convert_md_msg)
-            .sink_map_err(GError::Websocket)
-            .map_err(GError::Websocket);
+            .sink_map_err(websocket_error)
+            .map_err(websocket_error);
+
+        stream.send(sub.to_message()?).await?;
+
+        Ok(stream)
+    }
+
+    /// Connect to the legacy v1 market data feed for a single symbol.
+    ///
+    /// Unlike v2, every message carries a `socket_sequence`, and block
+    /// trades are reported.
+    pub async fn connect_v1_market_data(
+        uri: &str,
+        symbol: &str,
+        options: &V1MarketDataOptions,
+    ) -> Result<impl GeminiStream<V1MarketDataMessage>, GError> {
+        let url = format!("{}/v1/marketdata/{}?{}", uri, symbol, options.query());
+
+        let (stream, _resp) = connect_async(url).await.map_err(handshake_error)?;
 
-        let subscribe_msg = serde_json::to_string(&sub).unwrap();
-        stream.send(TMessage::text(subscribe_msg)).await?;
+        let stream = stream
+            .try_filter(|msg| future::ready(msg.is_text()))
+            .map_ok(convert_v1_md_msg)
+            .map_err(websocket_error);
 
         Ok(stream)
     }
@@ -98,37 +292,178 @@ impl WSFeed {
         uri: &str,
         api_key: &str,
         api_secret: &str,
+    ) -> Result<impl GeminiStream<OrderMessage>, GError> {
+        Self::connect_private_order_events_with_options(
+            uri,
+            api_key,
+            api_secret,
+            &OrderEventsOptions::default(),
+        )
+        .await
+    }
+
+    /// Connect to the order events feed, receiving only the events
+    /// that match `options`.
+    pub async fn connect_private_order_events_with_options(
+        uri: &str,
+        api_key: &str,
+        api_secret: &str,
+        options: &OrderEventsOptions,
+    ) -> Result<impl GeminiStream<OrderMessage>, GError> {
+        Self::connect_private_order_events_with_recorder(uri, api_key, api_secret, options, None)
+            .await
+    }
+
+    /// Connect to the order events feed, recording every raw message
+    /// received with `recorder`.
+    pub async fn connect_private_order_events_recorded(
+        uri: &str,
+        api_key: &str,
+        api_secret: &str,
+        options: &OrderEventsOptions,
+        recorder: Recorder,
+    ) -> Result<impl GeminiStream<OrderMessage>, GError> {
+        Self::connect_private_order_events_with_recorder(
+            uri,
+            api_key,
+            api_secret,
+            options,
+            Some(recorder),
+        )
+        .await
+    }
+
+    async fn connect_private_order_events_with_recorder(
+        uri: &str,
+        api_key: &str,
+        api_secret: &str,
+        options: &OrderEventsOptions,
+        recorder: Option<Recorder>,
     ) -> Result<impl GeminiStream<OrderMessage>, GError> {
         let endpoint = "/v1/order/events";
-        let url = uri.to_string() + endpoint;
+        let url = uri.to_string() + endpoint + &options.query();
         let payload = {
-            let body = Payload::empty(&endpoint);
+            let body = Payload::empty(endpoint);
             let payload_str = serde_json::to_string(&body).map_err(GError::SerdeSer)?;
             base64::encode(&payload_str)
         };
 
         let signature = Private::sign(api_secret, &payload);
 
-        let req = hyper::Request::builder()
-            .uri(url)
-            .header("Content-Type", "text/plain")
-            .header("X-GEMINI-APIKEY", api_key)
-            .header("X-GEMINI-PAYLOAD", &payload)
-            .header("X-GEMINI-SIGNATURE", &signature)
-            .body(())
-            .unwrap();
+        // Start from the websocket upgrade request, which carries the
+        // Sec-WebSocket-* headers, and add the authentication headers.
+        let mut req = url.into_client_request().map_err(websocket_error)?;
+        let headers = [
+            ("Content-Type", "text/plain"),
+            ("X-GEMINI-APIKEY", api_key),
+            ("X-GEMINI-PAYLOAD", &payload),
+            ("X-GEMINI-SIGNATURE", &signature),
+        ];
+        for (name, value) in headers.iter() {
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| websocket_error(TError::HttpFormat(e.into())))?;
+            req.headers_mut().insert(*name, value);
+        }
 
-        let (stream, _resp) = connect_async(req)
-            .await
-            .map_err(GError::Websocket)
-            .expect("connect-async");
+        let (stream, _resp) = connect_async(req).await.map_err(handshake_error)?;
 
         let stream = stream
             .try_filter(|msg| future::ready(msg.is_text()))
+            .inspect_ok(move |msg| record(recorder.as_ref(), msg))
             .map_ok(convert_order_msg)
-            .sink_map_err(GError::Websocket)
-            .map_err(GError::Websocket);
+            .sink_map_err(websocket_error)
+            .map_err(websocket_error);
 
         Ok(stream)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+    use crate::structs::wsfeed::CandleTimeFrame;
+
+    #[tokio::test]
+    async fn rejected_handshake_reports_the_gemini_reason() {
+        let server = MockServer::start("key", "secret").await.unwrap();
+        server.error("/v1/order/events", 400, "InvalidSignature", "Bad signature");
+        let uri = server.uri().replacen("http", "ws", 1);
+
+        match WSFeed::connect_private_order_events(&uri, "key", "secret").await {
+            Err(GError::Handshake {
+                status,
+                error: Some(error),
+            }) => {
+                assert_eq!(status, 400);
+                assert_eq!(error.reason, "InvalidSignature");
+                assert_eq!(error.message, "Bad signature");
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("handshake should fail"),
+        }
+    }
+
+    #[test]
+    fn order_events_query_is_encoded() {
+        assert_eq!(OrderEventsOptions::default().query(), "");
+
+        let options = OrderEventsOptions {
+            symbols: vec!["btcusd".to_string(), "eth usd".to_string()],
+            api_sessions: vec!["account-a&b=c".to_string()],
+            event_types: vec![OrderEventType::Fill, OrderEventType::CancelRejected],
+            heartbeat: true,
+        };
+        assert_eq!(
+            options.query(),
+            "?symbolFilter=btcusd&symbolFilter=eth+usd&apiSessionFilter=account-a%26b%3Dc\
+             &eventTypeFilter=fill&eventTypeFilter=cancel_rejected&heartbeat=true"
+        );
+    }
+
+    #[test]
+    fn perpetual_and_candle_subscriptions() {
+        let subscriptions = [
+            SubscriptionType::MarkPrice,
+            SubscriptionType::FundingAmount,
+            SubscriptionType::Candles1m,
+            SubscriptionType::Candles1d,
+        ]
+        .iter()
+        .map(|name| Subscription {
+            name: *name,
+            symbols: vec!["BTCGUSDPERP".to_string()],
+        })
+        .collect::<Vec<_>>();
+        let msg = serde_json::to_value(Subscribe::subscribe_to(&subscriptions)).unwrap();
+        let names: Vec<&str> = msg["subscriptions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            ["mark_price", "funding_amount", "candles_1m", "candles_1d"]
+        );
+
+        let decode = |text: &str| convert_md_msg(TMessage::text(text));
+        let mark = r#"{"type":"mark_price_updates","symbol":"BTCGUSDPERP",
+            "changes":[{"timestamp":1,"mark_price":"100.5"}]}"#;
+        assert!(matches!(decode(mark), MarketDataMessage::MarkPrice(m) if m.changes.len() == 1));
+        let funding = r#"{"type":"funding_amount_updates","symbol":"BTCGUSDPERP",
+            "changes":[{"timestamp":1,"funding_amount":"0.1"}]}"#;
+        assert!(
+            matches!(decode(funding), MarketDataMessage::FundingAmount(f) if f.changes.len() == 1)
+        );
+        let candles = r#"{"type":"candles_1d_updates","symbol":"BTCUSD",
+            "changes":[[1,100,110,90,105,2.5]]}"#;
+        match decode(candles) {
+            MarketDataMessage::Candles(c) => {
+                assert_eq!(c.time_frame, CandleTimeFrame::OneDay);
+                assert_eq!(c.changes[0].close, "105");
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+}